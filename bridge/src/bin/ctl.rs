@@ -0,0 +1,614 @@
+use std::{
+    collections::HashMap,
+    sync::mpsc::{self, Receiver, RecvTimeoutError},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context};
+use base64::Engine;
+use chrono::Utc;
+use ring::signature::Ed25519KeyPair;
+use rumqttc::{Client, Event, Packet, Publish, QoS};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+// The notification payload is shared with the firmware, like in the bridge
+#[allow(dead_code)]
+#[path = "../../../src/feed.rs"]
+mod feed;
+#[path = "../mqtt.rs"]
+mod mqtt;
+#[allow(dead_code)]
+#[path = "../ota.rs"]
+mod ota;
+
+use ota::Encoding;
+
+const USAGE: &str = "usage:
+  tramcast-ctl push <secret key> <image> [--version <version>] [--compress | --delta <base image>] [--force]
+  tramcast-ctl watch
+  tramcast-ctl confirm
+  tramcast-ctl rollback <secret key>
+  tramcast-ctl devices
+  tramcast-ctl command <JSON> [--key <secret key>]
+  tramcast-ctl notify <text> [--icon <icon>] [--priority <priority>] [--expires-in <seconds>] [--retain]
+  tramcast-ctl screenshot <output.pbm>
+
+The broker is set with --broker <url>, or TRAMCAST_BROKER, mqtt://localhost:1883 by default.
+The display is set with --device <client ID>, or TRAMCAST_DEVICE, `watch` follows all of them
+without it. Dangerous commands, like reboot, are signed with the secret key.";
+
+// Received by every display
+const BROADCAST_COMMAND_TOPIC: &str = "tramcast/command";
+const NOTIFICATION_TOPIC: &str = "tramcast/notification";
+// Under `tramcast/<client ID>/` of a display, see `topic`
+const COMMAND_TOPIC: &str = "command";
+const RESPONSE_TOPIC: &str = "command/response";
+const ROLLBACK_TOPIC: &str = "rollback";
+
+const DEFAULT_BROKER: &str = "mqtt://localhost:1883";
+// How long the broker and the device have to answer
+const TIMEOUT: Duration = Duration::from_secs(10);
+// Every device answers the status command, the ones answering within this are listed
+const DEVICES_WAIT: Duration = Duration::from_secs(3);
+// With the header and the topic, a chunk fits into the 1024 byte MQTT buffer of the device
+const CHUNK_LEN: usize = 960;
+// Chunks sent ahead of the last ack
+const WINDOW: usize = 8;
+// Times in a row the chunks are resent without an ack, before giving up
+const RETRIES: u32 = 5;
+// A new image reports `success` once it passed the health check, see docs/ota.md
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(360);
+const DEFAULT_EXPIRES_IN_SECS: i64 = 60;
+
+#[derive(Deserialize)]
+struct Screenshot {
+    width: usize,
+    height: usize,
+    data: String,
+}
+
+fn main() -> anyhow::Result<()> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+    let mut args: Vec<String> = std::env::args().collect();
+    let broker = option(&mut args, "--broker")?
+        .or_else(|| std::env::var("TRAMCAST_BROKER").ok())
+        .unwrap_or_else(|| DEFAULT_BROKER.to_string());
+    let device = option(&mut args, "--device")?
+        .or_else(|| std::env::var("TRAMCAST_DEVICE").ok())
+        .map(|device| {
+            anyhow::ensure!(
+                !device.is_empty() && !device.contains(['/', '+', '#']),
+                "invalid --device {:?}, the client ID of a display",
+                device
+            );
+            Ok(device)
+        })
+        .transpose()?;
+    // Commands and updates are sent to a single display
+    let target = || {
+        device
+            .as_deref()
+            .context("pass --device <client ID>, see `tramcast-ctl devices`")
+    };
+    let key_path = option(&mut args, "--key")?;
+    let version = option(&mut args, "--version")?;
+    let base_path = option(&mut args, "--delta")?;
+    let icon = option(&mut args, "--icon")?;
+    let priority = option(&mut args, "--priority")?;
+    let expires_in = option(&mut args, "--expires-in")?;
+    let compress = flag(&mut args, "--compress");
+    let force = flag(&mut args, "--force");
+    let retain = flag(&mut args, "--retain");
+
+    match &args[1..] {
+        [command, secret_path, image_path] if command == "push" => {
+            let key_pair = ota::read_key_pair(&read(secret_path)?)?;
+            let image = read(image_path)?;
+            let version = version.as_deref().unwrap_or("-");
+            let (mut manifest, payload) = match base_path {
+                Some(base_path) => {
+                    let base = read(&base_path)?;
+                    let payload = ota::compress(&ota::delta(&base, &image))?;
+                    let encoding = Encoding::Delta {
+                        payload: &payload,
+                        base: &base,
+                    };
+                    let manifest = ota::manifest(&key_pair, &image, version, encoding, force)?;
+                    (manifest, payload)
+                }
+                None if compress => {
+                    let payload = ota::compress(&image)?;
+                    let encoding = Encoding::Zlib(&payload);
+                    let manifest = ota::manifest(&key_pair, &image, version, encoding, force)?;
+                    (manifest, payload)
+                }
+                None => {
+                    let manifest = ota::manifest(&key_pair, &image, version, Encoding::Raw, force)?;
+                    (manifest, image)
+                }
+            };
+            sign(&mut manifest, &key_pair)?;
+            push(&broker, target()?, manifest, &payload)?;
+        }
+        [command] if command == "watch" => watch(&broker, device.as_deref())?,
+        [command] if command == "confirm" => {
+            let topic = topic(target()?, ota::CONFIRM_TOPIC);
+            Broker::connect(&broker, &[])?.publish(&topic, false, "success")?;
+            log::info!("Confirmed the running image");
+        }
+        [command, secret_path] if command == "rollback" => {
+            let key_pair = ota::read_key_pair(&read(secret_path)?)?;
+            let auth = ota::sign_command(&key_pair, "rollback")?;
+            Broker::connect(&broker, &[])?.publish(
+                &topic(target()?, ROLLBACK_TOPIC),
+                false,
+                serde_json::to_vec(&auth)?,
+            )?;
+            log::info!("Requested a rollback to the previous image");
+        }
+        [command] if command == "devices" => devices(&broker)?,
+        [command, request] if command == "command" => {
+            let device = target()?;
+            let mut request: Value =
+                serde_json::from_str(request).context("invalid command JSON")?;
+            if let Some(key_path) = key_path {
+                sign(&mut request, &ota::read_key_pair(&read(&key_path)?)?)?;
+            } else if ota::signed_action(&request).is_some() {
+                bail!(
+                    "{} must be signed, pass --key <secret key>",
+                    request["command"].as_str().unwrap_or_default()
+                );
+            }
+            let data = Broker::connect(&broker, &[topic(device, RESPONSE_TOPIC)])?
+                .request(device, request)?;
+            if data.is_null() {
+                log::info!("Done");
+            } else {
+                println!("{}", serde_json::to_string_pretty(&data)?);
+            }
+        }
+        [command, text] if command == "notify" => {
+            let expires_in = match expires_in {
+                Some(secs) => secs.parse().context("invalid --expires-in")?,
+                None => DEFAULT_EXPIRES_IN_SECS,
+            };
+            let notification = feed::Notification {
+                text: text.clone(),
+                icon: icon
+                    .map(|icon| serde_json::from_value(icon.into()))
+                    .transpose()
+                    .context("invalid --icon, one of tram, warning or info")?,
+                priority: priority
+                    .map(|priority| serde_json::from_value(priority.into()))
+                    .transpose()
+                    .context("invalid --priority, normal or high")?
+                    .unwrap_or_default(),
+                expires_at: Utc::now() + chrono::Duration::seconds(expires_in),
+                // So a redelivered older notification doesn't replace this one
+                generated_at: Some(Utc::now()),
+                seq: None,
+            };
+            Broker::connect(&broker, &[])?.publish(
+                NOTIFICATION_TOPIC,
+                retain,
+                serde_json::to_vec(&notification)?,
+            )?;
+            log::info!("Sent the notification, expiring in {} seconds", expires_in);
+        }
+        [command, output] if command == "screenshot" => {
+            let device = target()?;
+            let data = Broker::connect(&broker, &[topic(device, RESPONSE_TOPIC)])?
+                .request(device, json!({ "command": "screenshot" }))?;
+            let screenshot: Screenshot = serde_json::from_value(data)?;
+            let frame = base64::engine::general_purpose::STANDARD.decode(&screenshot.data)?;
+            anyhow::ensure!(
+                frame.len() == screenshot.width * screenshot.height / 8,
+                "the screenshot is {} bytes, not {}x{} pixels",
+                frame.len(),
+                screenshot.width,
+                screenshot.height
+            );
+            // In PBM 1 is black, lit pixels are inverted so they are white, as on the display
+            let mut pbm = format!("P4\n{} {}\n", screenshot.width, screenshot.height).into_bytes();
+            pbm.extend(frame.iter().map(|byte| !byte));
+            write(output, &pbm)?;
+            log::info!("Wrote the screenshot to {}", output);
+        }
+        _ => bail!(USAGE),
+    }
+    Ok(())
+}
+
+// Adds the signature to a dangerous command, see docs/mqtt.md
+fn sign(command: &mut Value, key_pair: &Ed25519KeyPair) -> anyhow::Result<()> {
+    if let Some(action) = ota::signed_action(command) {
+        command["auth"] = ota::sign_command(key_pair, &action)?;
+    }
+    Ok(())
+}
+
+// A topic of the display with the client ID `device`, `+` matching any of them
+fn topic(device: &str, name: &str) -> String {
+    format!("tramcast/{}/{}", device, name)
+}
+
+// The client ID of the display a message on `topic(device, name)` is from
+fn device_of<'a>(topic: &'a str, name: &str) -> Option<&'a str> {
+    topic
+        .strip_prefix("tramcast/")?
+        .strip_suffix(name)?
+        .strip_suffix('/')
+        .filter(|device| !device.contains('/'))
+}
+
+// A connection to the broker, receiving the messages of `topics`
+struct Broker {
+    url: String,
+    client: Client,
+    rx: Receiver<Packet>,
+    // Correlates command responses
+    next_id: u32,
+}
+
+impl Broker {
+    fn connect(url: &str, topics: &[String]) -> anyhow::Result<Self> {
+        // Unique, so several instances can be connected at once
+        let client_id = format!("tramcast-ctl-{}", std::process::id());
+        let options = mqtt::options(url, &client_id)?;
+        let (client, mut connection) = Client::new(options, 10);
+        let (tx, rx) = mpsc::channel();
+        {
+            let client = client.clone();
+            let topics = topics.to_vec();
+            thread::spawn(move || {
+                // Polling the connection drives the client, and reconnects after errors
+                for event in connection.iter() {
+                    match event {
+                        // The session is clean, so subscriptions are renewed after reconnecting
+                        Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                            for topic in &topics {
+                                if let Err(e) = client.try_subscribe(topic, QoS::AtLeastOnce) {
+                                    log::error!("Failed to subscribe to {}: {}", topic, e);
+                                }
+                            }
+                        }
+                        Ok(Event::Incoming(packet)) => {
+                            if tx.send(packet).is_err() {
+                                return;
+                            }
+                        }
+                        Ok(Event::Outgoing(_)) => {}
+                        Err(e) => {
+                            log::error!("MQTT error: {}", e);
+                            thread::sleep(Duration::from_secs(5));
+                        }
+                    }
+                }
+            });
+        }
+
+        let broker = Self {
+            url: url.to_string(),
+            client,
+            rx,
+            next_id: 0,
+        };
+        // Otherwise responses to commands sent right away could be missed
+        let deadline = Instant::now() + TIMEOUT;
+        let mut subscribed = 0;
+        while subscribed < topics.len() {
+            match broker.recv(deadline)? {
+                Some(Packet::SubAck(_)) => subscribed += 1,
+                Some(_) => {}
+                None => bail!(
+                    "failed to subscribe within {:?}, is {} reachable?",
+                    TIMEOUT,
+                    url
+                ),
+            }
+        }
+        Ok(broker)
+    }
+
+    // The next packet, `None` once `deadline` passed
+    fn recv(&self, deadline: Instant) -> anyhow::Result<Option<Packet>> {
+        match self
+            .rx
+            .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+        {
+            Ok(packet) => Ok(Some(packet)),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => bail!("the connection to {} closed", self.url),
+        }
+    }
+
+    // The next message of the subscribed topics
+    fn message(&self, deadline: Instant) -> anyhow::Result<Option<Publish>> {
+        loop {
+            match self.recv(deadline)? {
+                Some(Packet::Publish(message)) => return Ok(Some(message)),
+                Some(_) => {}
+                None => return Ok(None),
+            }
+        }
+    }
+
+    // Waits until the broker received the message, so it isn't lost when the process exits
+    fn publish(
+        &self,
+        topic: &str,
+        retain: bool,
+        payload: impl Into<Vec<u8>>,
+    ) -> anyhow::Result<()> {
+        self.client
+            .publish(topic, QoS::AtLeastOnce, retain, payload)?;
+        let deadline = Instant::now() + TIMEOUT;
+        loop {
+            match self.recv(deadline)? {
+                Some(Packet::PubAck(_)) => return Ok(()),
+                Some(_) => {}
+                None => bail!(
+                    "failed to publish within {:?}, is {} reachable?",
+                    TIMEOUT,
+                    self.url
+                ),
+            }
+        }
+    }
+
+    // Publishes a command to `topic`, returning the ID its responses have
+    fn send_command(&mut self, topic: &str, mut command: Value) -> anyhow::Result<String> {
+        self.next_id += 1;
+        let id = format!("tramcast-ctl-{}-{}", std::process::id(), self.next_id);
+        command["id"] = id.as_str().into();
+        self.client.publish(
+            topic,
+            QoS::AtLeastOnce,
+            false,
+            serde_json::to_vec(&command)?,
+        )?;
+        Ok(id)
+    }
+
+    // The next response to the command with `id`, and the display it is from
+    fn response(&self, id: &str, deadline: Instant) -> anyhow::Result<Option<(String, Value)>> {
+        while let Some(message) = self.message(deadline)? {
+            let Some(device) = device_of(&message.topic, RESPONSE_TOPIC) else {
+                continue;
+            };
+            match serde_json::from_slice::<Value>(&message.payload) {
+                Ok(response) if response["id"] == id => {
+                    return Ok(Some((device.to_string(), response)))
+                }
+                Ok(_) => {}
+                Err(e) => log::warn!("Invalid command response from {}: {}", device, e),
+            }
+        }
+        Ok(None)
+    }
+
+    // The data of the response of `device`, or its error
+    fn request(&mut self, device: &str, command: Value) -> anyhow::Result<Value> {
+        let id = self.send_command(&topic(device, COMMAND_TOPIC), command)?;
+        let (_, response) = self
+            .response(&id, Instant::now() + TIMEOUT)?
+            .with_context(|| format!("no response within {:?}, is {} online?", TIMEOUT, device))?;
+        if response["result"] != "ok" {
+            bail!(
+                "the device refused: {}",
+                response["error"].as_str().unwrap_or_default()
+            );
+        }
+        Ok(response["data"].clone())
+    }
+}
+
+// Logs the progress of an update in steps of 10%, prefixed with the display when watching
+// several
+struct Progress {
+    device: Option<String>,
+    reported: usize,
+}
+
+impl Progress {
+    fn update(&mut self, done: usize, total: usize) {
+        let percent = done * 100 / total.max(1);
+        if percent / 10 != self.reported / 10 || (done == total && self.reported != 100) {
+            match &self.device {
+                Some(device) => log::info!("{}: {}/{} bytes ({}%)", device, done, total, percent),
+                None => log::info!("{}/{} bytes ({}%)", done, total, percent),
+            }
+            self.reported = percent;
+        }
+    }
+}
+
+// Publishes the payload in chunks, each acked by the device, then waits for the result
+fn push(url: &str, device: &str, manifest: Value, payload: &[u8]) -> anyhow::Result<()> {
+    let data_topic = topic(device, ota::DATA_TOPIC);
+    let ack_topic = topic(device, ota::ACK_TOPIC);
+    let result_topic = topic(device, ota::RESULT_TOPIC);
+    let mut broker = Broker::connect(
+        url,
+        &[
+            topic(device, RESPONSE_TOPIC),
+            ack_topic.clone(),
+            result_topic.clone(),
+        ],
+    )?;
+    let version = manifest["version"].as_str().unwrap_or_default().to_string();
+    // An interrupted update of the same image continues where the device stopped
+    let ack: ota::Ack = serde_json::from_value(broker.request(device, manifest)?)?;
+    if ack.offset > 0 {
+        log::info!("Resuming the update to {} at {}", version, ack.offset);
+    } else {
+        log::info!("Updating to {}", version);
+    }
+
+    let mut acked = ack.offset;
+    let mut sent = acked;
+    let mut seq = 0;
+    // Where the chunks end, by sequence number
+    let mut ends = HashMap::new();
+    // Acks of chunks sent before resending are ignored
+    let mut resent_at = 0;
+    let mut retries = 0;
+    let mut progress = Progress {
+        device: None,
+        reported: 0,
+    };
+    while acked < payload.len() {
+        while sent < payload.len() && sent < acked + WINDOW * CHUNK_LEN {
+            let end = (sent + CHUNK_LEN).min(payload.len());
+            let chunk = ota::chunk(seq, sent, &payload[sent..end]);
+            broker
+                .client
+                .publish(&data_topic, QoS::AtLeastOnce, false, chunk)?;
+            ends.insert(seq, end);
+            seq += 1;
+            sent = end;
+        }
+
+        let Some(message) = broker.message(Instant::now() + TIMEOUT)? else {
+            retries += 1;
+            if retries > RETRIES {
+                bail!("no ack within {:?}, {} times in a row", TIMEOUT, RETRIES);
+            }
+            log::warn!("No ack within {:?}, resending from {}", TIMEOUT, acked);
+            sent = acked;
+            resent_at = seq;
+            continue;
+        };
+        match &message.topic {
+            topic if *topic == result_topic => check_result(&message.payload)?,
+            topic if *topic == ack_topic => {
+                let ack: ota::Ack = match serde_json::from_slice(&message.payload) {
+                    Ok(ack) => ack,
+                    Err(e) => {
+                        log::warn!("Invalid ack: {}", e);
+                        continue;
+                    }
+                };
+                retries = 0;
+                // Moves back when the device resumed from its last saved offset
+                acked = ack.offset;
+                // The chunk was dropped, so are the ones sent after it
+                let dropped = ends.get(&ack.seq).is_some_and(|end| ack.offset < *end);
+                if (dropped && ack.seq >= resent_at) || sent < acked {
+                    sent = acked;
+                    resent_at = seq;
+                }
+                progress.update(acked, payload.len());
+            }
+            _ => {}
+        }
+    }
+
+    log::info!(
+        "Sent {}, waiting for the device to verify it and pass the health check",
+        version
+    );
+    let deadline = Instant::now() + HEALTH_CHECK_TIMEOUT;
+    while let Some(message) = broker.message(deadline)? {
+        if message.topic == result_topic {
+            check_result(&message.payload)?;
+            log::info!("Updated to {}", version);
+            return Ok(());
+        }
+    }
+    bail!(
+        "no result within {:?}, the device may have rolled back",
+        HEALTH_CHECK_TIMEOUT
+    )
+}
+
+// Fails unless the result is `success`
+fn check_result(payload: &[u8]) -> anyhow::Result<()> {
+    let result = String::from_utf8_lossy(payload);
+    anyhow::ensure!(result == "success", "the update failed: {}", result);
+    Ok(())
+}
+
+// Logs the progress and the results of updates of `device`, or of every display, until
+// interrupted
+fn watch(url: &str, device: Option<&str>) -> anyhow::Result<()> {
+    let device = device.unwrap_or("+");
+    let broker = Broker::connect(
+        url,
+        &[
+            topic(device, ota::ACK_TOPIC),
+            topic(device, ota::RESULT_TOPIC),
+        ],
+    )?;
+    log::info!("Watching updates on {}", url);
+    let mut progress: HashMap<String, Progress> = HashMap::new();
+    loop {
+        let Some(message) = broker.message(Instant::now() + TIMEOUT)? else {
+            continue;
+        };
+        if let Some(device) = device_of(&message.topic, ota::RESULT_TOPIC) {
+            let result = String::from_utf8_lossy(&message.payload);
+            log::info!("{}: result: {}", device, result);
+            progress.remove(device);
+        } else if let Some(device) = device_of(&message.topic, ota::ACK_TOPIC) {
+            match serde_json::from_slice::<ota::Ack>(&message.payload) {
+                Ok(ack) => progress
+                    .entry(device.to_string())
+                    .or_insert(Progress {
+                        device: Some(device.to_string()),
+                        reported: 0,
+                    })
+                    .update(ack.offset, ack.size),
+                Err(e) => log::warn!("Invalid ack from {}: {}", device, e),
+            }
+        }
+    }
+}
+
+// Lists the devices answering the status command
+fn devices(url: &str) -> anyhow::Result<()> {
+    let mut broker = Broker::connect(url, &[topic("+", RESPONSE_TOPIC)])?;
+    let id = broker.send_command(BROADCAST_COMMAND_TOPIC, json!({ "command": "status" }))?;
+    let deadline = Instant::now() + DEVICES_WAIT;
+    let mut count = 0;
+    while let Some((device, response)) = broker.response(&id, deadline)? {
+        let status = &response["data"];
+        println!(
+            "{:<24} {:<32} up {:>8}s  {}",
+            device,
+            status["version"].as_str().unwrap_or_default(),
+            status["uptime_secs"],
+            status["broker"].as_str().unwrap_or_default()
+        );
+        count += 1;
+    }
+    anyhow::ensure!(count > 0, "no device answered within {:?}", DEVICES_WAIT);
+    Ok(())
+}
+
+// Removes `name` and its value from the arguments
+fn option(args: &mut Vec<String>, name: &str) -> anyhow::Result<Option<String>> {
+    let Some(index) = args.iter().position(|arg| arg == name) else {
+        return Ok(None);
+    };
+    anyhow::ensure!(index + 1 < args.len(), "{} needs a value", name);
+    args.remove(index);
+    Ok(Some(args.remove(index)))
+}
+
+fn flag(args: &mut Vec<String>, name: &str) -> bool {
+    let found = args.iter().any(|arg| arg == name);
+    args.retain(|arg| arg != name);
+    found
+}
+
+fn read(path: &str) -> anyhow::Result<Vec<u8>> {
+    std::fs::read(path).with_context(|| format!("failed to read {}", path))
+}
+
+fn write(path: &str, data: &[u8]) -> anyhow::Result<()> {
+    std::fs::write(path, data).with_context(|| format!("failed to write {}", path))
+}
@@ -0,0 +1,116 @@
+use anyhow::{bail, Context};
+use ring::{
+    rand::SystemRandom,
+    signature::{Ed25519KeyPair, KeyPair},
+};
+
+#[allow(dead_code)]
+#[path = "../ota.rs"]
+mod ota;
+
+use ota::{compress, delta, manifest, read_key_pair, Encoding};
+
+const USAGE: &str = "usage:
+  tramcast-sign keygen <secret key> <public key>
+  tramcast-sign sign <secret key> <image> <version> <manifest> [url] [--force]
+  tramcast-sign compress <secret key> <image> <version> <manifest> <payload> [url] [--force]
+  tramcast-sign delta <secret key> <base image> <image> <version> <manifest> <payload> [url] [--force]
+
+The version `-` is the one embedded into the image. --force allows downgrades.";
+
+fn main() -> anyhow::Result<()> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+    let mut args: Vec<String> = std::env::args().collect();
+    let force = args.iter().any(|arg| arg == "--force");
+    args.retain(|arg| arg != "--force");
+    match &args[1..] {
+        [command, secret_path, public_path] if command == "keygen" => {
+            // PKCS#8, the public key is raw, as embedded into the firmware
+            let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+                .map_err(|_| anyhow::anyhow!("failed to generate a key"))?;
+            let key_pair = read_key_pair(pkcs8.as_ref())?;
+            write(secret_path, pkcs8.as_ref())?;
+            write(public_path, key_pair.public_key().as_ref())?;
+            log::info!(
+                "Wrote the secret key to {}, and the public key to {}",
+                secret_path,
+                public_path
+            );
+        }
+        [command, secret_path, image_path, version, output, url @ ..]
+            if command == "sign" && url.len() <= 1 =>
+        {
+            let key_pair = read_key_pair(&read(secret_path)?)?;
+            let image = read(image_path)?;
+            let manifest = manifest(&key_pair, &image, version, Encoding::Raw, force)?;
+            write_manifest(output, manifest, url.first())?;
+        }
+        [command, secret_path, image_path, version, output, payload_path, url @ ..]
+            if command == "compress" && url.len() <= 1 =>
+        {
+            let key_pair = read_key_pair(&read(secret_path)?)?;
+            let image = read(image_path)?;
+            let payload = compress(&image)?;
+            let manifest = manifest(&key_pair, &image, version, Encoding::Zlib(&payload), force)?;
+            write(payload_path, &payload)?;
+            log::info!(
+                "Wrote {} bytes compressed from {} to {}",
+                payload.len(),
+                image.len(),
+                payload_path
+            );
+            write_manifest(output, manifest, url.first())?;
+        }
+        [command, secret_path, base_path, image_path, version, output, payload_path, url @ ..]
+            if command == "delta" && url.len() <= 1 =>
+        {
+            let key_pair = read_key_pair(&read(secret_path)?)?;
+            let base = read(base_path)?;
+            let image = read(image_path)?;
+            let payload = compress(&delta(&base, &image))?;
+            let encoding = Encoding::Delta {
+                payload: &payload,
+                base: &base,
+            };
+            let manifest = manifest(&key_pair, &image, version, encoding, force)?;
+            write(payload_path, &payload)?;
+            log::info!(
+                "Wrote a {} byte delta from {} to {}",
+                payload.len(),
+                base_path,
+                payload_path
+            );
+            write_manifest(output, manifest, url.first())?;
+        }
+        _ => bail!(USAGE),
+    }
+    Ok(())
+}
+
+// The device downloads the image from `url` instead of receiving it from the broker
+fn write_manifest(
+    path: &str,
+    mut manifest: serde_json::Value,
+    url: Option<&String>,
+) -> anyhow::Result<()> {
+    if let Some(url) = url {
+        manifest["command"] = "ota_download".into();
+        manifest["url"] = url.as_str().into();
+    }
+    write(path, &serde_json::to_vec_pretty(&manifest)?)?;
+    log::info!(
+        "Wrote the manifest of {} to {}",
+        manifest["version"].as_str().unwrap_or_default(),
+        path
+    );
+    Ok(())
+}
+
+fn read(path: &str) -> anyhow::Result<Vec<u8>> {
+    std::fs::read(path).with_context(|| format!("failed to read {}", path))
+}
+
+fn write(path: &str, data: &[u8]) -> anyhow::Result<()> {
+    std::fs::write(path, data).with_context(|| format!("failed to write {}", path))
+}
@@ -0,0 +1,111 @@
+use std::{collections::HashMap, path::Path};
+
+use anyhow::{bail, Context};
+use serde::Deserialize;
+
+#[allow(dead_code)]
+#[path = "../schedule.rs"]
+mod schedule;
+#[allow(dead_code)]
+#[path = "../../../src/timetable.rs"]
+mod timetable;
+
+use timetable::{Departure, Timetable};
+
+// The stops of the screens, read from the config.yml of the firmware
+#[derive(Deserialize)]
+struct Config {
+    #[serde(default)]
+    gtfs_tram_stop_ids: Vec<String>,
+    #[serde(default)]
+    gtfs_tram_route_ids: Vec<String>,
+    #[serde(default)]
+    gtfs_metro_stop_ids: Vec<String>,
+    #[serde(default)]
+    gtfs_metro_route_ids: Vec<String>,
+}
+
+fn main() -> anyhow::Result<()> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+    let args: Vec<String> = std::env::args().collect();
+    let [_, config_path, gtfs_dir, output] = &args[..] else {
+        bail!("usage: tramcast-timetable <config.yml> <GTFS directory> <output>");
+    };
+    let config_file = std::fs::read_to_string(config_path)
+        .with_context(|| format!("failed to read {}", config_path))?;
+    let config: Config = serde_yaml::from_str(&config_file)?;
+
+    let mut timetable = Timetable::default();
+    // Indices of the GTFS service IDs in `timetable.services`
+    let mut services = HashMap::new();
+    timetable.tram = compile(
+        Path::new(gtfs_dir),
+        &config.gtfs_tram_stop_ids,
+        &config.gtfs_tram_route_ids,
+        &mut timetable,
+        &mut services,
+    )?;
+    timetable.metro = compile(
+        Path::new(gtfs_dir),
+        &config.gtfs_metro_stop_ids,
+        &config.gtfs_metro_route_ids,
+        &mut timetable,
+        &mut services,
+    )?;
+    anyhow::ensure!(
+        !timetable.is_empty(),
+        "no scheduled departures, check gtfs_tram_stop_ids and gtfs_metro_stop_ids"
+    );
+
+    let data = postcard::to_allocvec(&timetable)?;
+    std::fs::write(output, &data).with_context(|| format!("failed to write {}", output))?;
+    log::info!(
+        "Wrote {} tram and {} metro departures of {} services to {}, {} bytes",
+        timetable.tram.len(),
+        timetable.metro.len(),
+        timetable.services.len(),
+        output,
+        data.len()
+    );
+    Ok(())
+}
+
+// The departures of a screen, adding the services they run on to the timetable
+fn compile(
+    dir: &Path,
+    stop_ids: &[String],
+    route_ids: &[String],
+    timetable: &mut Timetable,
+    services: &mut HashMap<String, u16>,
+) -> anyhow::Result<Vec<Departure>> {
+    if stop_ids.is_empty() {
+        return Ok(Vec::new());
+    }
+    let schedule = schedule::Timetable::load(dir, stop_ids, route_ids)?;
+    timetable.timezone = schedule.timezone.name().to_string();
+
+    let mut departures = Vec::new();
+    for scheduled in &schedule.departures {
+        let Some(service) = schedule.services.get(&scheduled.service_id) else {
+            log::warn!("Service {} has no calendar", scheduled.service_id);
+            continue;
+        };
+        let index = match services.get(&scheduled.service_id) {
+            Some(index) => *index,
+            None => {
+                let index = u16::try_from(timetable.services.len())?;
+                timetable.services.push(service.clone());
+                services.insert(scheduled.service_id.clone(), index);
+                index
+            }
+        };
+        departures.push(Departure {
+            service: index,
+            time: scheduled.time,
+        });
+    }
+    departures.sort_by_key(|departure| (departure.time, departure.service));
+    departures.dedup_by_key(|departure| (departure.time, departure.service));
+    Ok(departures)
+}
@@ -0,0 +1,23 @@
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+
+use crate::json_feed;
+
+// A JSON departures API, e.g. BKK FUTÁR, configured like the `http_feeds` of the firmware
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HttpSource {
+    url: String,
+    // JSON pointer to the list of departures
+    departures: String,
+    // Fields of a departure holding its UNIX time, the first one present is used
+    time_fields: Vec<String>,
+}
+
+impl HttpSource {
+    // The earliest departure in the response that is not in the past
+    pub fn next_departure(&self, now: DateTime<Utc>) -> anyhow::Result<Option<DateTime<Utc>>> {
+        let document: serde_json::Value = ureq::get(&self.url).call()?.into_json()?;
+        json_feed::next_departure(&document, &self.departures, &self.time_fields, now)
+    }
+}
@@ -0,0 +1,209 @@
+use std::{path::PathBuf, thread, time::Duration};
+
+use chrono::{DateTime, Utc};
+use rumqttc::{Client, QoS};
+use serde::Deserialize;
+
+// The payload types and the GTFS-Realtime messages are shared with the firmware, so the
+// published feeds always match what the display deserializes
+#[allow(dead_code)]
+#[path = "../../src/feed.rs"]
+mod feed;
+#[allow(dead_code)]
+#[path = "../../src/gtfs.rs"]
+mod gtfs;
+mod http;
+#[path = "../../src/json_feed.rs"]
+mod json_feed;
+mod mqtt;
+mod realtime;
+mod schedule;
+#[allow(dead_code)]
+#[path = "../../src/timetable.rs"]
+mod timetable;
+
+use feed::{Metro, Tram};
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    mqtt_endpoint: String,
+    #[serde(default = "default_mqtt_client_id")]
+    mqtt_client_id: String,
+    #[serde(default = "default_interval_secs")]
+    interval_secs: u64,
+    // Publishes the time of the host, for displays that can't reach a NTP server
+    #[serde(default = "default_publish_time")]
+    publish_time: bool,
+    // Sources are written as `gtfs: ...` instead of YAML tags
+    #[serde(default, with = "serde_yaml::with::singleton_map")]
+    tram: Option<Source>,
+    #[serde(default, with = "serde_yaml::with::singleton_map")]
+    metro: Option<Source>,
+}
+
+fn default_mqtt_client_id() -> String {
+    "tramcast-bridge".to_string()
+}
+
+fn default_interval_secs() -> u64 {
+    30
+}
+
+fn default_publish_time() -> bool {
+    true
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+enum Source {
+    Http(http::HttpSource),
+    Gtfs(GtfsSource),
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct GtfsSource {
+    // Directory of an extracted GTFS static feed
+    static_dir: Option<PathBuf>,
+    // URL or path of a GTFS-Realtime TripUpdates feed
+    realtime: Option<String>,
+    stop_ids: Vec<String>,
+    // Optional, trips of any route are used when empty
+    #[serde(default)]
+    route_ids: Vec<String>,
+}
+
+// Encodes the payload of a topic from the next departure, the time left until it, and
+// when it was computed
+type Encode = fn(Option<DateTime<Utc>>, Option<i64>, DateTime<Utc>) -> serde_json::Result<Vec<u8>>;
+
+// A configured source, with its timetable loaded
+enum Feed {
+    Http(http::HttpSource),
+    Gtfs {
+        source: GtfsSource,
+        timetable: Option<schedule::Timetable>,
+    },
+}
+
+impl Feed {
+    fn load(source: Source) -> anyhow::Result<Self> {
+        Ok(match source {
+            Source::Http(source) => Self::Http(source),
+            Source::Gtfs(source) => {
+                anyhow::ensure!(
+                    source.static_dir.is_some() || source.realtime.is_some(),
+                    "a GTFS source needs static_dir, realtime or both"
+                );
+                anyhow::ensure!(!source.stop_ids.is_empty(), "stop_ids must not be empty");
+                let timetable = source
+                    .static_dir
+                    .as_ref()
+                    .map(|dir| schedule::Timetable::load(dir, &source.stop_ids, &source.route_ids))
+                    .transpose()?;
+                Self::Gtfs { source, timetable }
+            }
+        })
+    }
+
+    fn next_departure(&self, now: DateTime<Utc>) -> anyhow::Result<Option<DateTime<Utc>>> {
+        match self {
+            Self::Http(source) => source.next_departure(now),
+            Self::Gtfs { source, timetable } => {
+                let realtime = source
+                    .realtime
+                    .as_deref()
+                    .map(realtime::fetch)
+                    .transpose()?;
+                Ok(realtime::next_departure(
+                    timetable.as_ref(),
+                    realtime.as_ref(),
+                    &source.stop_ids,
+                    &source.route_ids,
+                    now,
+                ))
+            }
+        }
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+    let config_path = std::env::args().nth(1).unwrap_or("bridge.yml".to_string());
+    let config_file = std::fs::read_to_string(&config_path)
+        .map_err(|e| anyhow::anyhow!("failed to read {}: {}", config_path, e))?;
+    let config: Config = serde_yaml::from_str(&config_file)?;
+
+    // Topics the firmware subscribes to, see docs/mqtt.md
+    let mut feeds: Vec<(&str, Encode, Feed)> = Vec::new();
+    if let Some(source) = config.tram {
+        let encode: Encode = |depart_at, time_left_ms, generated_at| {
+            serde_json::to_vec(&Tram {
+                depart_at,
+                time_left_ms,
+                generated_at: Some(generated_at),
+                seq: None,
+            })
+        };
+        feeds.push(("villamos", encode, Feed::load(source)?));
+    }
+    if let Some(source) = config.metro {
+        let encode: Encode = |depart_at, time_left_ms, generated_at| {
+            serde_json::to_vec(&Metro {
+                depart_at,
+                time_left_ms,
+                generated_at: Some(generated_at),
+                seq: None,
+            })
+        };
+        feeds.push(("metro", encode, Feed::load(source)?));
+    }
+    anyhow::ensure!(!feeds.is_empty(), "no feeds configured");
+
+    let options = mqtt::options(&config.mqtt_endpoint, &config.mqtt_client_id)?;
+    let (client, mut connection) = Client::new(options, 10);
+    thread::spawn(move || {
+        // Polling the connection drives the client, and reconnects after errors
+        for event in connection.iter() {
+            if let Err(e) = event {
+                log::error!("MQTT error: {}", e);
+                thread::sleep(Duration::from_secs(5));
+            }
+        }
+    });
+
+    loop {
+        if config.publish_time {
+            let now = Utc::now().to_rfc3339();
+            if let Err(e) = client.try_publish("tramcast/time", QoS::AtMostOnce, true, now) {
+                log::error!("Failed to publish the time: {}", e);
+            }
+        }
+
+        for (topic, encode, feed) in &feeds {
+            let now = Utc::now();
+            // Failures are only logged, the retained payload stays on the broker
+            let depart_at = match feed.next_departure(now) {
+                Ok(depart_at) => depart_at,
+                Err(e) => {
+                    log::error!("Failed to get {} departure: {:?}", topic, e);
+                    continue;
+                }
+            };
+            log::info!("Next {} departure: {:?}", topic, depart_at);
+
+            let time_left_ms = depart_at.map(|at| (at - now).num_milliseconds());
+            // The generation timestamp lets displays drop redelivered older payloads
+            let payload = encode(depart_at, time_left_ms, now)?;
+            // Retained, so displays are populated right after connecting. Doesn't block
+            // while the broker is unreachable, the next update replaces a dropped one.
+            if let Err(e) = client.try_publish(*topic, QoS::AtLeastOnce, true, payload) {
+                log::error!("Failed to publish {} departure: {}", topic, e);
+            }
+        }
+
+        thread::sleep(Duration::from_secs(config.interval_secs));
+    }
+}
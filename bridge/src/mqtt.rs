@@ -0,0 +1,18 @@
+use rumqttc::MqttOptions;
+
+// The options of a broker URL, with `client_id` added to its query, since rumqttc takes the
+// client ID from there. The URL may have a query of its own, e.g. `?keep_alive_secs=30`,
+// and a `client_id` in it is replaced.
+pub fn options(endpoint: &str, client_id: &str) -> anyhow::Result<MqttOptions> {
+    let mut url = url::Url::parse(endpoint)?;
+    let query: Vec<(String, String)> = url
+        .query_pairs()
+        .filter(|(name, _)| name != "client_id")
+        .map(|(name, value)| (name.into_owned(), value.into_owned()))
+        .collect();
+    url.query_pairs_mut()
+        .clear()
+        .extend_pairs(query)
+        .append_pair("client_id", client_id);
+    Ok(MqttOptions::try_from(url)?)
+}
@@ -0,0 +1,257 @@
+// Signing and encoding of OTA images, shared by `tramcast-sign` and `tramcast-ctl`
+
+use std::{collections::HashMap, io::Write};
+
+use anyhow::Context;
+use base64::Engine;
+use flate2::{write::ZlibEncoder, Compression};
+use ring::{
+    digest,
+    rand::{SecureRandom, SystemRandom},
+    signature::Ed25519KeyPair,
+};
+use serde::Deserialize;
+use serde_json::Value;
+
+// Under `tramcast/<client ID>/` of a display, see docs/ota.md
+pub const RESULT_TOPIC: &str = "ota/result";
+pub const DATA_TOPIC: &str = "ota/data";
+pub const ACK_TOPIC: &str = "ota/ack";
+pub const CONFIRM_TOPIC: &str = "ota/confirm";
+
+// Published by the device for every chunk received
+#[derive(Deserialize, Debug)]
+pub struct Ack {
+    pub seq: u32,
+    // Received in order so far, where the sender continues from
+    pub offset: usize,
+    pub size: usize,
+}
+
+// Precedes the version in the image, see src/version.rs
+const VERSION_MARKER: &[u8] = b"tramcast-version:";
+
+// Delta ops, see src/decode.rs
+const OP_COPY: u8 = 0;
+const OP_INSERT: u8 = 1;
+// Shorter matches are inserted, a copy op takes 9 bytes
+const MIN_COPY_LEN: usize = 32;
+// Matches are looked up by blocks of this many bytes
+const BLOCK_LEN: usize = 16;
+
+// ESP-IDF targets by the chip ID in the image header
+const CHIPS: &[(u16, &str)] = &[
+    (0x0000, "esp32"),
+    (0x0002, "esp32s2"),
+    (0x0005, "esp32c3"),
+    (0x0009, "esp32s3"),
+    (0x000C, "esp32c2"),
+    (0x000D, "esp32c6"),
+    (0x0010, "esp32h2"),
+];
+
+// How the image is sent, with the data sent unless it is the image itself
+pub enum Encoding<'a> {
+    Raw,
+    Zlib(&'a [u8]),
+    Delta { payload: &'a [u8], base: &'a [u8] },
+}
+
+// A message on `DATA_TOPIC`: the sequence number and the offset, then the data
+pub fn chunk(seq: u32, offset: usize, data: &[u8]) -> Vec<u8> {
+    let mut chunk = Vec::with_capacity(8 + data.len());
+    chunk.extend_from_slice(&seq.to_le_bytes());
+    chunk.extend_from_slice(&(offset as u32).to_le_bytes());
+    chunk.extend_from_slice(data);
+    chunk
+}
+
+// The `ota_begin` command announcing the image, see src/ota.rs
+pub fn manifest(
+    key_pair: &Ed25519KeyPair,
+    image: &[u8],
+    version: &str,
+    encoding: Encoding,
+    force: bool,
+) -> anyhow::Result<Value> {
+    anyhow::ensure!(
+        image.len() > 14 && image[0] == 0xE9,
+        "not an ESP-IDF app image, convert it with espflash save-image"
+    );
+    let chip_id = u16::from_le_bytes([image[12], image[13]]);
+    let chip = CHIPS
+        .iter()
+        .find(|(id, _)| *id == chip_id)
+        .map(|(_, chip)| *chip)
+        .with_context(|| format!("unknown chip ID {}", chip_id))?;
+    let sha256 = hex(digest::digest(&digest::SHA256, image).as_ref());
+    let version = match version {
+        "-" => embedded_version(image)
+            .context("no version found in the image, built by an older firmware?")?,
+        version => version.to_string(),
+    };
+
+    let mut manifest = serde_json::json!({
+        "command": "ota_begin",
+        "size": image.len(),
+        "sha256": sha256,
+        "version": version,
+        "chip": chip,
+    });
+    match encoding {
+        Encoding::Raw => {}
+        Encoding::Zlib(payload) => {
+            manifest["encoding"] = "zlib".into();
+            manifest["transfer_size"] = payload.len().into();
+        }
+        Encoding::Delta { payload, base } => {
+            manifest["encoding"] = "delta".into();
+            manifest["transfer_size"] = payload.len().into();
+            manifest["base_size"] = base.len().into();
+            manifest["base_sha256"] = hex(digest::digest(&digest::SHA256, base).as_ref()).into();
+        }
+    }
+    if force {
+        manifest["force"] = true.into();
+    }
+    let signature = key_pair.sign(signed_message(&manifest).as_bytes());
+    manifest["signature"] = base64::engine::general_purpose::STANDARD
+        .encode(signature)
+        .into();
+    Ok(manifest)
+}
+
+// Must match `Manifest::signed_message` of the firmware
+fn signed_message(manifest: &Value) -> String {
+    let size = manifest["size"].as_u64().unwrap_or_default();
+    let transfer_size = manifest["transfer_size"].as_u64().unwrap_or(size);
+    let message = format!(
+        "tramcast-ota:{}:{}:{}:{}",
+        size,
+        manifest["sha256"].as_str().unwrap_or_default(),
+        manifest["chip"].as_str().unwrap_or_default(),
+        manifest["version"].as_str().unwrap_or_default()
+    );
+    let message = match manifest["encoding"].as_str() {
+        Some("zlib") => format!("{}:zlib:{}", message, transfer_size),
+        Some("delta") => format!(
+            "{}:delta:{}:{}:{}",
+            message,
+            transfer_size,
+            manifest["base_size"].as_u64().unwrap_or_default(),
+            manifest["base_sha256"].as_str().unwrap_or_default()
+        ),
+        _ => message,
+    };
+    if manifest["force"] == true {
+        format!("{}:force", message)
+    } else {
+        message
+    }
+}
+
+// What the signature of a dangerous command covers, `None` if it needn't be signed. Must
+// match `Command::signed_action` of the firmware.
+pub fn signed_action(command: &Value) -> Option<String> {
+    match command["command"].as_str()? {
+        action @ ("reboot" | "factory_reset") => Some(action.to_string()),
+        "ota_begin" => Some(format!("ota_begin:{}", signed_message(command))),
+        "ota_download" => Some(format!(
+            "ota_download:{}:{}",
+            signed_message(command),
+            command["url"].as_str().unwrap_or_default()
+        )),
+        _ => None,
+    }
+}
+
+// The `auth` of a dangerous command, or the payload of a rollback, see
+// docs/mqtt.md. Must match `auth::signed_message` of the firmware.
+pub fn sign_command(key_pair: &Ed25519KeyPair, action: &str) -> anyhow::Result<Value> {
+    let mut nonce = [0; 16];
+    SystemRandom::new()
+        .fill(&mut nonce)
+        .map_err(|_| anyhow::anyhow!("failed to generate a nonce"))?;
+    let nonce = hex(&nonce);
+    let timestamp = chrono::Utc::now().timestamp();
+    let message = format!("tramcast-command:{}:{}:{}", timestamp, nonce, action);
+    Ok(serde_json::json!({
+        "nonce": nonce,
+        "timestamp": timestamp,
+        "signature": base64::engine::general_purpose::STANDARD.encode(key_pair.sign(message.as_bytes())),
+    }))
+}
+
+fn embedded_version(image: &[u8]) -> Option<String> {
+    let start = image
+        .windows(VERSION_MARKER.len())
+        .position(|window| window == VERSION_MARKER)?
+        + VERSION_MARKER.len();
+    let len = image[start..].iter().position(|&byte| byte == 0)?;
+    String::from_utf8(image[start..start + len].to_vec()).ok()
+}
+
+pub fn compress(data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::best());
+    encoder.write_all(data)?;
+    Ok(encoder.finish()?)
+}
+
+// Ops rebuilding `image` from `base`: runs found in the base are copied, the rest inserted
+pub fn delta(base: &[u8], image: &[u8]) -> Vec<u8> {
+    // The last offset of each block in the base
+    let mut blocks = HashMap::new();
+    for (offset, block) in base.windows(BLOCK_LEN).enumerate() {
+        blocks.insert(block, offset);
+    }
+
+    let mut ops = Vec::new();
+    let mut inserted = Vec::new();
+    let mut pos = 0;
+    while pos < image.len() {
+        let copy = image
+            .get(pos..pos + BLOCK_LEN)
+            .and_then(|block| blocks.get(block))
+            .map(|&offset| {
+                let len = base[offset..]
+                    .iter()
+                    .zip(&image[pos..])
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                (offset, len)
+            })
+            .filter(|(_, len)| *len >= MIN_COPY_LEN);
+        match copy {
+            Some((offset, len)) => {
+                push_insert(&mut ops, &mut inserted);
+                ops.push(OP_COPY);
+                ops.extend_from_slice(&(offset as u32).to_le_bytes());
+                ops.extend_from_slice(&(len as u32).to_le_bytes());
+                pos += len;
+            }
+            None => {
+                inserted.push(image[pos]);
+                pos += 1;
+            }
+        }
+    }
+    push_insert(&mut ops, &mut inserted);
+    ops
+}
+
+fn push_insert(ops: &mut Vec<u8>, inserted: &mut Vec<u8>) {
+    if inserted.is_empty() {
+        return;
+    }
+    ops.push(OP_INSERT);
+    ops.extend_from_slice(&(inserted.len() as u32).to_le_bytes());
+    ops.append(inserted);
+}
+
+pub fn read_key_pair(pkcs8: &[u8]) -> anyhow::Result<Ed25519KeyPair> {
+    Ed25519KeyPair::from_pkcs8(pkcs8).map_err(|e| anyhow::anyhow!("invalid secret key: {}", e))
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
@@ -0,0 +1,129 @@
+use std::{collections::HashMap, io::Read};
+
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use prost::Message;
+
+use crate::{
+    gtfs::{
+        self, FeedMessage, StopFilter, StopScheduleRelationship, StopTimeUpdate,
+        TripScheduleRelationship, TripUpdate,
+    },
+    schedule::{ScheduledDeparture, Timetable},
+};
+
+// How long before now scheduled trips are still considered, in case they are delayed
+const MAX_DELAY_HOURS: i64 = 2;
+
+// Reads a TripUpdates feed from a URL or a file
+pub fn fetch(source: &str) -> anyhow::Result<FeedMessage> {
+    let mut data = Vec::new();
+    if source.starts_with("http://") || source.starts_with("https://") {
+        ureq::get(source)
+            .call()?
+            .into_reader()
+            .read_to_end(&mut data)?;
+    } else {
+        data = std::fs::read(source)?;
+    }
+    Ok(FeedMessage::decode(data.as_slice())?)
+}
+
+// The earliest departure from the stops that is not in the past. Scheduled trips are
+// adjusted by their TripUpdates, trips missing from the timetable are taken as-is.
+pub fn next_departure(
+    timetable: Option<&Timetable>,
+    realtime: Option<&FeedMessage>,
+    stop_ids: &[String],
+    route_ids: &[String],
+    now: DateTime<Utc>,
+) -> Option<DateTime<Utc>> {
+    let trip_updates: HashMap<&str, &TripUpdate> = realtime
+        .iter()
+        .flat_map(|feed| &feed.entity)
+        .filter(|entity| !entity.is_deleted())
+        .filter_map(|entity| entity.trip_update.as_ref())
+        .filter_map(|trip_update| Some((trip_update.trip.trip_id.as_deref()?, trip_update)))
+        .collect();
+
+    let scheduled = timetable.and_then(|timetable| {
+        timetable
+            .departures(
+                now - Duration::hours(MAX_DELAY_HOURS),
+                now + Duration::days(1),
+            )
+            .into_iter()
+            .filter_map(
+                |(departure, time)| match trip_updates.get(departure.trip_id.as_str()) {
+                    Some(trip_update) => adjust(departure, time, trip_update),
+                    None => Some(time),
+                },
+            )
+            .filter(|time| *time >= now)
+            .min()
+    });
+
+    // Added trips, or all of them without a timetable, matched the same way as on the display
+    let unscheduled = realtime.and_then(|feed| {
+        let mut feed = feed.clone();
+        feed.entity.retain(|entity| {
+            let trip_id = entity
+                .trip_update
+                .as_ref()
+                .and_then(|trip_update| trip_update.trip.trip_id.as_deref());
+            !trip_id.is_some_and(|trip_id| timetable.is_some_and(|t| t.has_trip(trip_id)))
+        });
+        let stop_ids = stop_ids.join(",");
+        let route_ids = route_ids.join(",");
+        let filter = StopFilter::parse(&stop_ids, &route_ids)?;
+        gtfs::next_departure(&feed, &filter, now)
+    });
+
+    scheduled.into_iter().chain(unscheduled).min()
+}
+
+// The departure of a scheduled trip adjusted by its TripUpdate, `None` if it doesn't stop
+fn adjust(
+    departure: &ScheduledDeparture,
+    scheduled: DateTime<Utc>,
+    trip_update: &TripUpdate,
+) -> Option<DateTime<Utc>> {
+    if trip_update.trip.schedule_relationship() == TripScheduleRelationship::Canceled {
+        return None;
+    }
+
+    let is_at_stop = |update: &StopTimeUpdate| {
+        update.stop_sequence.map_or(
+            update.stop_id.as_deref() == Some(departure.stop_id.as_str()),
+            |sequence| sequence == departure.stop_sequence,
+        )
+    };
+    // Updates are ordered by stop sequence, the delay of the closest preceding stop
+    // carries over to the following ones
+    let update = trip_update
+        .stop_time_update
+        .iter()
+        .take_while(|update| {
+            update
+                .stop_sequence
+                .map_or(true, |sequence| sequence <= departure.stop_sequence)
+        })
+        .filter(|update| update.stop_sequence.is_some() || is_at_stop(update))
+        .last();
+    let Some(update) = update else {
+        return Some(scheduled);
+    };
+
+    match update.schedule_relationship() {
+        StopScheduleRelationship::Skipped if is_at_stop(update) => return None,
+        StopScheduleRelationship::NoData => return Some(scheduled),
+        _ => {}
+    }
+    let Some(event) = update.departure.as_ref().or(update.arrival.as_ref()) else {
+        return Some(scheduled);
+    };
+    match (event.time, event.delay) {
+        (Some(time), _) if is_at_stop(update) => Utc.timestamp_opt(time, 0).single(),
+        (_, Some(delay)) => Some(scheduled + Duration::seconds(delay.into())),
+        _ => Some(scheduled),
+    }
+}
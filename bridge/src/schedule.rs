@@ -0,0 +1,221 @@
+use std::{collections::HashMap, path::Path};
+
+use anyhow::Context;
+use chrono::{DateTime, Datelike, Duration, NaiveDate, Utc};
+use chrono_tz::Tz;
+use serde::Deserialize;
+
+use crate::timetable::{self, Service};
+
+// The scheduled departures from a set of stops, loaded from a GTFS static feed,
+// see https://gtfs.org/schedule/reference/
+pub struct Timetable {
+    pub timezone: Tz,
+    // By service ID
+    pub services: HashMap<String, Service>,
+    pub departures: Vec<ScheduledDeparture>,
+}
+
+pub struct ScheduledDeparture {
+    pub trip_id: String,
+    pub stop_id: String,
+    pub stop_sequence: u32,
+    pub service_id: String,
+    // Seconds since noon minus 12 hours of the service day, may be over 24 hours
+    pub time: u32,
+}
+
+#[derive(Deserialize)]
+struct AgencyRow {
+    agency_timezone: String,
+}
+
+#[derive(Deserialize)]
+struct TripRow {
+    route_id: String,
+    service_id: String,
+    trip_id: String,
+}
+
+#[derive(Deserialize)]
+struct StopTimeRow {
+    trip_id: String,
+    arrival_time: Option<String>,
+    departure_time: Option<String>,
+    stop_id: String,
+    stop_sequence: u32,
+    pickup_type: Option<u8>,
+}
+
+#[derive(Deserialize)]
+struct CalendarRow {
+    service_id: String,
+    monday: u8,
+    tuesday: u8,
+    wednesday: u8,
+    thursday: u8,
+    friday: u8,
+    saturday: u8,
+    sunday: u8,
+    start_date: String,
+    end_date: String,
+}
+
+#[derive(Deserialize)]
+struct CalendarDateRow {
+    service_id: String,
+    date: String,
+    exception_type: u8,
+}
+
+impl Timetable {
+    // Only keeps the departures from `stop_ids`, of `route_ids` if not empty
+    pub fn load(dir: &Path, stop_ids: &[String], route_ids: &[String]) -> anyhow::Result<Self> {
+        let agency: AgencyRow = read(dir, "agency.txt")?
+            .deserialize()
+            .next()
+            .context("agency.txt is empty")??;
+        let timezone: Tz = agency
+            .agency_timezone
+            .parse()
+            .map_err(|e| anyhow::anyhow!("invalid agency_timezone: {}", e))?;
+
+        let mut trips = HashMap::new();
+        for row in read(dir, "trips.txt")?.deserialize() {
+            let row: TripRow = row?;
+            if route_ids.is_empty() || route_ids.contains(&row.route_id) {
+                trips.insert(row.trip_id, row.service_id);
+            }
+        }
+
+        let mut departures = Vec::new();
+        for row in read(dir, "stop_times.txt")?.deserialize() {
+            let row: StopTimeRow = row?;
+            // Passengers can't board when the pickup type is 1
+            if !stop_ids.contains(&row.stop_id) || row.pickup_type == Some(1) {
+                continue;
+            }
+            let Some(service_id) = trips.get(&row.trip_id) else {
+                continue;
+            };
+            // Untimed stops are interpolated by consumers, they are left out instead
+            let Some(time) = row.departure_time.or(row.arrival_time) else {
+                continue;
+            };
+            departures.push(ScheduledDeparture {
+                service_id: service_id.clone(),
+                trip_id: row.trip_id,
+                stop_id: row.stop_id,
+                stop_sequence: row.stop_sequence,
+                time: parse_time(&time)?,
+            });
+        }
+
+        // At least one of the calendar files is present in a valid feed
+        let mut services: HashMap<String, Service> = HashMap::new();
+        if dir.join("calendar.txt").exists() {
+            for row in read(dir, "calendar.txt")?.deserialize() {
+                let row: CalendarRow = row?;
+                let service = services.entry(row.service_id).or_default();
+                // Bit 0 is Monday
+                service.weekdays = [
+                    row.monday,
+                    row.tuesday,
+                    row.wednesday,
+                    row.thursday,
+                    row.friday,
+                    row.saturday,
+                    row.sunday,
+                ]
+                .iter()
+                .enumerate()
+                .fold(0, |weekdays, (day, runs)| {
+                    weekdays | (u8::from(*runs == 1) << day)
+                });
+                service.start_date = Some(parse_date(&row.start_date)?.num_days_from_ce());
+                service.end_date = Some(parse_date(&row.end_date)?.num_days_from_ce());
+            }
+        }
+        if dir.join("calendar_dates.txt").exists() {
+            for row in read(dir, "calendar_dates.txt")?.deserialize() {
+                let row: CalendarDateRow = row?;
+                let service = services.entry(row.service_id).or_default();
+                let date = parse_date(&row.date)?.num_days_from_ce();
+                match row.exception_type {
+                    1 => service.added.push(date),
+                    _ => service.removed.push(date),
+                }
+            }
+        }
+
+        log::info!(
+            "Loaded {} scheduled departures from {}",
+            departures.len(),
+            dir.display()
+        );
+        Ok(Self {
+            timezone,
+            services,
+            departures,
+        })
+    }
+
+    pub fn has_trip(&self, trip_id: &str) -> bool {
+        self.departures
+            .iter()
+            .any(|departure| departure.trip_id == trip_id)
+    }
+
+    // Departures between `from` and `until`, with their scheduled time
+    pub fn departures(
+        &self,
+        from: DateTime<Utc>,
+        until: DateTime<Utc>,
+    ) -> Vec<(&ScheduledDeparture, DateTime<Utc>)> {
+        // Trips of the previous service day may run past midnight
+        let first_day = from.with_timezone(&self.timezone).date_naive() - Duration::days(1);
+        let last_day = until.with_timezone(&self.timezone).date_naive();
+
+        let mut departures = Vec::new();
+        for day in first_day.iter_days().take_while(|day| *day <= last_day) {
+            let Some(start) = timetable::service_day_start(self.timezone, day) else {
+                continue;
+            };
+            for departure in &self.departures {
+                let runs = self
+                    .services
+                    .get(&departure.service_id)
+                    .is_some_and(|service| service.runs_on(day));
+                let time = start + Duration::seconds(departure.time.into());
+                if runs && from <= time && time <= until {
+                    departures.push((departure, time));
+                }
+            }
+        }
+        departures
+    }
+}
+
+fn read(dir: &Path, file: &str) -> anyhow::Result<csv::Reader<std::fs::File>> {
+    let path = dir.join(file);
+    csv::Reader::from_path(&path).with_context(|| format!("failed to read {}", path.display()))
+}
+
+// HH:MM:SS, where the hours may be over 24 for trips past midnight
+fn parse_time(time: &str) -> anyhow::Result<u32> {
+    let parts: Vec<u32> = time
+        .trim()
+        .split(':')
+        .map(|part| part.parse())
+        .collect::<Result<_, _>>()
+        .with_context(|| format!("invalid time {}", time))?;
+    let [hours, minutes, seconds] = parts[..] else {
+        anyhow::bail!("invalid time {}", time);
+    };
+    Ok(hours * 3600 + minutes * 60 + seconds)
+}
+
+// YYYYMMDD
+fn parse_date(date: &str) -> anyhow::Result<NaiveDate> {
+    NaiveDate::parse_from_str(date, "%Y%m%d").with_context(|| format!("invalid date {}", date))
+}
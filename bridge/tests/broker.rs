@@ -0,0 +1,99 @@
+// Failover between the brokers of the firmware, see docs/mqtt.md#failover
+
+#[path = "../../src/broker.rs"]
+mod broker;
+
+use std::{net::TcpListener, time::Duration};
+
+use broker::{Brokers, Poll};
+
+fn brokers_of(endpoints: Vec<&'static str>) -> Brokers {
+    Brokers::new(endpoints, Duration::ZERO, Duration::ZERO)
+}
+
+#[test]
+fn address() {
+    assert_eq!(
+        broker::address("mqtt://192.168.1.10:1883"),
+        Some(("192.168.1.10", 1883))
+    );
+    assert_eq!(
+        broker::address("mqtts://user:p@ss@broker.example.com"),
+        Some(("broker.example.com", 8883))
+    );
+    assert_eq!(
+        broker::address("wss://broker.example.com/mqtt?x=1"),
+        Some(("broker.example.com", 443))
+    );
+    assert_eq!(broker::address("ws://[::1]:8080/mqtt"), Some(("::1", 8080)));
+    assert_eq!(broker::address("mqtt://[fe80::1]"), Some(("fe80::1", 1883)));
+    assert_eq!(
+        broker::address("mqtt://user:password@[::1]:1884"),
+        Some(("::1", 1884))
+    );
+}
+
+#[test]
+fn invalid_address() {
+    assert_eq!(broker::address("192.168.1.10:1883"), None);
+    assert_eq!(broker::address("mqtt://[::1"), None);
+    assert_eq!(broker::address("mqtt://[::1]1883"), None);
+    assert_eq!(broker::address("mqtt://::1:1883"), None);
+    assert_eq!(broker::address("mqtt://:1883"), None);
+    assert_eq!(broker::address("mqtt://broker:port"), None);
+}
+
+#[test]
+fn reachable() {
+    let v4 = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = v4.local_addr().unwrap().port();
+    assert!(broker::is_reachable(&format!("mqtt://127.0.0.1:{}", port)));
+    let v6 = TcpListener::bind("[::1]:0").unwrap();
+    let port = v6.local_addr().unwrap().port();
+    assert!(broker::is_reachable(&format!("mqtt://[::1]:{}", port)));
+    drop(v6);
+    assert!(!broker::is_reachable(&format!("mqtt://[::1]:{}", port)));
+}
+
+#[test]
+fn failover() {
+    let mut brokers = brokers_of(vec!["mqtt://primary", "mqtt://fallback"]);
+    brokers.connecting();
+    assert_eq!(brokers.poll(), Poll::Switched);
+    assert_eq!(brokers.active(), "mqtt://fallback");
+    brokers.connecting();
+    assert_eq!(brokers.poll(), Poll::Switched);
+    assert_eq!(brokers.active(), "mqtt://primary");
+
+    // A single broker is retried
+    let mut brokers = brokers_of(vec!["mqtt://primary"]);
+    brokers.connecting();
+    assert_eq!(brokers.poll(), Poll::Stay);
+}
+
+#[test]
+fn probe() {
+    let mut brokers = brokers_of(vec!["mqtt://primary", "mqtt://fallback"]);
+    brokers.set_connected(true);
+    // Connected to the primary
+    assert_eq!(brokers.poll(), Poll::Stay);
+    assert!(!brokers.primary_reachable());
+
+    brokers.set_connected(false);
+    assert_eq!(brokers.poll(), Poll::Switched);
+    brokers.set_connected(true);
+    assert_eq!(brokers.poll(), Poll::Probe("mqtt://primary"));
+    assert!(brokers.primary_reachable());
+    assert_eq!(brokers.active(), "mqtt://primary");
+}
+
+#[test]
+fn disconnected_while_probing() {
+    let mut brokers = brokers_of(vec!["mqtt://primary", "mqtt://fallback"]);
+    assert_eq!(brokers.poll(), Poll::Switched);
+    brokers.set_connected(true);
+    assert_eq!(brokers.poll(), Poll::Probe("mqtt://primary"));
+    brokers.set_connected(false);
+    assert!(!brokers.primary_reachable());
+    assert_eq!(brokers.active(), "mqtt://fallback");
+}
@@ -0,0 +1,221 @@
+// OTA downloads resumed against a local HTTP file server, the way the download thread
+// does, see docs/ota.md#download
+
+#[allow(dead_code)]
+#[path = "../../src/resume.rs"]
+mod resume;
+
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::TcpListener,
+    sync::mpsc::{self, Receiver},
+    thread,
+    time::Duration,
+};
+
+use resume::{Failure, Retries};
+
+enum Reply {
+    // The image from the requested range, or all of it without range support, cut off after
+    // this many bytes of the body
+    File { ranges: bool, cut_at: Option<usize> },
+    Status(u16),
+}
+
+fn image() -> Vec<u8> {
+    (0..10_000).map(|i| (i * 31 % 251) as u8).collect()
+}
+
+// Serves the replies in order, one per connection, and sends back the `Range` headers
+fn serve(replies: Vec<Reply>) -> (String, Receiver<Option<String>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/firmware.bin", listener.local_addr().unwrap());
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let image = image();
+        for reply in replies {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut range = None;
+            for line in BufReader::new(&stream).lines() {
+                let line = line.unwrap();
+                if line.is_empty() {
+                    break;
+                }
+                if let Some((name, value)) = line.split_once(": ") {
+                    if name.eq_ignore_ascii_case("range") {
+                        range = Some(value.to_string());
+                    }
+                }
+            }
+
+            let start = range
+                .as_deref()
+                .and_then(|range| range.strip_prefix("bytes="))
+                .and_then(|range| range.strip_suffix('-'))
+                .map(|start| start.parse::<usize>().unwrap());
+            tx.send(range).unwrap();
+            match reply {
+                Reply::File { ranges, cut_at } => {
+                    let (status, start) = match start {
+                        Some(start) if ranges => ("206 Partial Content", start),
+                        _ => ("200 OK", 0),
+                    };
+                    let body = &image[start..];
+                    write!(
+                        stream,
+                        "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                        status,
+                        body.len()
+                    )
+                    .unwrap();
+                    let end = cut_at.unwrap_or(body.len());
+                    stream.write_all(&body[..end]).unwrap();
+                }
+                Reply::Status(status) => {
+                    write!(
+                        stream,
+                        "HTTP/1.1 {} Mock\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                        status
+                    )
+                    .unwrap();
+                }
+            }
+        }
+    });
+    (url, rx)
+}
+
+// The rest of the image after what was written, like `download::fetch`
+fn fetch(url: &str, written: &mut Vec<u8>) -> Result<(), Failure> {
+    let network = |e: &dyn std::fmt::Display| Failure::Network(e.to_string());
+    let response = match ureq::get(url)
+        .set("Range", &resume::range(written.len()))
+        .call()
+    {
+        Ok(response) | Err(ureq::Error::Status(_, response)) => response,
+        Err(e) => return Err(network(&e)),
+    };
+    let mut offset = resume::body_offset(response.status(), written.len())?;
+    let mut reader = response.into_reader();
+    let mut buf = [0; 1024];
+    loop {
+        let len = reader.read(&mut buf).map_err(|e| network(&e))?;
+        if len == 0 {
+            return Ok(());
+        }
+        // What was already written is skipped, like `Update::write` does
+        let end = offset + len;
+        if end > written.len() {
+            let skipped = written.len() - offset;
+            written.extend_from_slice(&buf[skipped..len]);
+        }
+        offset = end;
+    }
+}
+
+// Retried like `download::download_thread`, without waiting
+fn download(url: &str) -> Result<(Vec<u8>, u32), String> {
+    let mut written = Vec::new();
+    let mut retries = Retries::default();
+    loop {
+        let before = written.len();
+        match fetch(url, &mut written) {
+            Ok(()) => return Ok((written, retries.count())),
+            Err(Failure::Network(e)) => {
+                if retries.next_delay(written.len() > before).is_none() {
+                    return Err(e);
+                }
+            }
+            Err(Failure::Rejected(e)) => return Err(e),
+            Err(Failure::Aborted) => unreachable!(),
+        }
+    }
+}
+
+#[test]
+fn resumed_with_range() {
+    let (url, ranges) = serve(vec![
+        Reply::File {
+            ranges: true,
+            cut_at: Some(3000),
+        },
+        Reply::File {
+            ranges: true,
+            cut_at: Some(4500),
+        },
+        Reply::File {
+            ranges: true,
+            cut_at: None,
+        },
+    ]);
+    let (written, retries) = download(&url).unwrap();
+    assert!(written == image());
+    assert_eq!(retries, 1);
+    let ranges: Vec<_> = ranges.try_iter().collect();
+    assert_eq!(
+        ranges,
+        [
+            Some("bytes=0-".to_string()),
+            Some("bytes=3000-".to_string()),
+            Some("bytes=7500-".to_string()),
+        ]
+    );
+}
+
+#[test]
+fn resumed_without_range_support() {
+    // The whole image is sent again, what was written is skipped
+    let (url, _ranges) = serve(vec![
+        Reply::File {
+            ranges: false,
+            cut_at: Some(3000),
+        },
+        Reply::File {
+            ranges: false,
+            cut_at: None,
+        },
+    ]);
+    let (written, _) = download(&url).unwrap();
+    assert!(written == image());
+}
+
+#[test]
+fn server_errors_retried() {
+    let (url, _ranges) = serve(vec![
+        Reply::Status(503),
+        Reply::File {
+            ranges: true,
+            cut_at: None,
+        },
+    ]);
+    let (written, retries) = download(&url).unwrap();
+    assert!(written == image());
+    assert_eq!(retries, 1);
+}
+
+#[test]
+fn client_errors_rejected() {
+    let (url, ranges) = serve(vec![Reply::Status(404)]);
+    assert_eq!(download(&url).unwrap_err(), "HTTP status 404");
+    assert_eq!(ranges.try_iter().count(), 1);
+}
+
+#[test]
+fn given_up() {
+    let replies = (0..=resume::RETRIES).map(|_| Reply::Status(500)).collect();
+    let (url, ranges) = serve(replies);
+    assert_eq!(download(&url).unwrap_err(), "HTTP status 500");
+    assert_eq!(ranges.try_iter().count(), resume::RETRIES as usize + 1);
+}
+
+#[test]
+fn retry_delays() {
+    let mut retries = Retries::default();
+    let delays: Vec<_> = (0..resume::RETRIES)
+        .map(|_| retries.next_delay(false).unwrap().as_secs())
+        .collect();
+    assert_eq!(delays, [5, 10, 15, 20, 25]);
+    assert_eq!(retries.next_delay(false), None);
+    // Receiving data starts over
+    assert_eq!(retries.next_delay(true), Some(Duration::from_secs(5)));
+}
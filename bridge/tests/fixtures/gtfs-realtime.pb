@@ -0,0 +1,32 @@
+
+
+2.04
+canceled(
+
+
+T-canceled *3040"F01234D
+skipped9
+
+	T-skipped*3040Ԣ"F01234("F09999*
+past"
+
+T-past*3060"F012348
+other-route)
+
+T-other-route*9999"F012342
+deleted%
+
+	T-deleted*3040"F012342
+
+delay-only$
+
+T-delay-only*3040<"F01234:
+arrival-only*
+
+T-arrival-only*3060"F012342
+tram*
+
+T-tram*3040"F01234,
+metro#
+
+T-metro*5200̣"F05678
\ No newline at end of file
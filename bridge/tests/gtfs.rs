@@ -0,0 +1,131 @@
+// Departures derived from a GTFS-Realtime feed, the way the display does, see
+// fixtures/gtfs-realtime.textproto for what the feed contains
+
+#[allow(dead_code)]
+#[path = "../../src/gtfs.rs"]
+mod gtfs;
+
+use chrono::{DateTime, Utc};
+use gtfs::{FeedMessage, StopFilter};
+use prost::Message;
+
+const FIXTURE: &[u8] = include_bytes!("fixtures/gtfs-realtime.pb");
+
+fn feed() -> FeedMessage {
+    FeedMessage::decode(FIXTURE).unwrap()
+}
+
+fn time(s: &str) -> DateTime<Utc> {
+    s.parse().unwrap()
+}
+
+fn next_departure(stop_ids: &str, route_ids: &str, now: &str) -> Option<DateTime<Utc>> {
+    let filter = StopFilter::parse(stop_ids, route_ids).unwrap();
+    gtfs::next_departure(&feed(), &filter, time(now))
+}
+
+#[test]
+fn header() {
+    let feed = feed();
+    assert_eq!(feed.header.gtfs_realtime_version, "2.0");
+    assert_eq!(feed.header.timestamp, Some(1710755990));
+    assert_eq!(feed.entity.len(), 9);
+}
+
+#[test]
+fn filter_without_stops() {
+    assert!(StopFilter::parse("", "3040").is_none());
+    assert!(StopFilter::parse(",,", "").is_none());
+    assert!(StopFilter::parse("F01234,", "").is_some());
+}
+
+#[test]
+fn route_filter() {
+    // The canceled trip, the skipped stop, the deleted entity and the one without an
+    // absolute time are passed over, leaving the arrival-only trip
+    assert_eq!(
+        next_departure("F01234", "3040,3060", "2024-03-18T10:00:00Z"),
+        Some(time("2024-03-18T10:06:00Z"))
+    );
+    assert_eq!(
+        next_departure("F01234", "3040", "2024-03-18T10:00:00Z"),
+        Some(time("2024-03-18T10:08:00Z"))
+    );
+    // Any route
+    assert_eq!(
+        next_departure("F01234", "", "2024-03-18T10:00:00Z"),
+        Some(time("2024-03-18T10:04:00Z"))
+    );
+    assert_eq!(
+        next_departure("F01234", "1234", "2024-03-18T10:00:00Z"),
+        None
+    );
+}
+
+#[test]
+fn stop_filter() {
+    assert_eq!(
+        next_departure("F05678", "", "2024-03-18T10:00:00Z"),
+        Some(time("2024-03-18T10:05:00Z"))
+    );
+    assert_eq!(
+        next_departure("F05678,F01234", "5200,3040", "2024-03-18T10:00:00Z"),
+        Some(time("2024-03-18T10:05:00Z"))
+    );
+    assert_eq!(next_departure("F00000", "", "2024-03-18T10:00:00Z"), None);
+}
+
+#[test]
+fn canceled_trip() {
+    // The trip still lists its stop time, 10:02
+    let feed = feed();
+    let canceled = feed.entity.iter().find(|e| e.id == "canceled").unwrap();
+    assert_eq!(
+        canceled.trip_update.as_ref().unwrap().stop_time_update[0]
+            .departure
+            .as_ref()
+            .unwrap()
+            .time,
+        Some(1710756120)
+    );
+    assert_ne!(
+        next_departure("F01234", "", "2024-03-18T10:00:00Z"),
+        Some(time("2024-03-18T10:02:00Z"))
+    );
+}
+
+#[test]
+fn skipped_stop() {
+    // Skipped at F01234 at 10:03, but the trip still stops at F09999
+    assert_ne!(
+        next_departure("F01234", "3040", "2024-03-18T10:00:00Z"),
+        Some(time("2024-03-18T10:03:00Z"))
+    );
+    assert_eq!(
+        next_departure("F09999", "3040", "2024-03-18T10:00:00Z"),
+        Some(time("2024-03-18T10:04:00Z"))
+    );
+}
+
+#[test]
+fn departure_over_arrival() {
+    // Arrives at 10:07:30, departs at 10:08
+    assert_eq!(
+        next_departure("F01234", "3040", "2024-03-18T10:07:00Z"),
+        Some(time("2024-03-18T10:08:00Z"))
+    );
+}
+
+#[test]
+fn past_departures() {
+    // 09:58 has passed, a departure right now is still shown
+    assert_eq!(
+        next_departure("F01234", "3060", "2024-03-18T09:57:00Z"),
+        Some(time("2024-03-18T09:58:00Z"))
+    );
+    assert_eq!(
+        next_departure("F01234", "3040,3060", "2024-03-18T10:06:00Z"),
+        Some(time("2024-03-18T10:06:00Z"))
+    );
+    assert_eq!(next_departure("F01234", "", "2024-03-18T10:08:01Z"), None);
+}
@@ -0,0 +1,133 @@
+// Feed payloads as the firmware decodes them, see docs/mqtt.md#payload-formats
+
+#[allow(dead_code)]
+#[path = "../../src/feed.rs"]
+mod feed;
+#[path = "../../src/payload.rs"]
+mod payload;
+
+use chrono::{DateTime, Utc};
+use feed::{Alert, Alerts, Icon, Metro, Notification, Priority, Severity, Tram};
+use serde::{de::DeserializeOwned, Serialize};
+
+fn time(s: &str) -> DateTime<Utc> {
+    s.parse().unwrap()
+}
+
+fn postcard<T: Serialize>(value: &T) -> Vec<u8> {
+    postcard::to_extend(value, vec![payload::POSTCARD_FORMAT]).unwrap()
+}
+
+// Decoded from both encodings, compared through JSON since the feed types aren't comparable
+fn assert_round_trip<T: Serialize + DeserializeOwned>(value: T) {
+    let expected = serde_json::to_value(&value).unwrap();
+    let from_json: T = payload::decode(&serde_json::to_vec(&value).unwrap()).unwrap();
+    assert_eq!(serde_json::to_value(from_json).unwrap(), expected);
+    let from_postcard: T = payload::decode(&postcard(&value)).unwrap();
+    assert_eq!(serde_json::to_value(from_postcard).unwrap(), expected);
+}
+
+fn alert() -> Alert {
+    Alert {
+        affected_lines: vec!["3".to_string(), "M2".to_string()],
+        severity: Severity::Warning,
+        header_text: "Track works".to_string(),
+        active_from: Some(time("2024-03-18T06:00:00Z")),
+        active_until: None,
+    }
+}
+
+#[test]
+fn tram() {
+    assert_round_trip(Tram {
+        depart_at: Some(time("2024-03-18T10:15:00Z")),
+        time_left_ms: Some(240_000),
+        generated_at: Some(time("2024-03-18T10:11:00Z")),
+        seq: Some(42),
+    });
+    assert_round_trip(Tram {
+        depart_at: None,
+        time_left_ms: None,
+        generated_at: None,
+        seq: None,
+    });
+}
+
+#[test]
+fn metro() {
+    assert_round_trip(Metro {
+        depart_at: Some(time("2024-03-18T10:15:00Z")),
+        time_left_ms: Some(-1_000),
+        generated_at: None,
+        seq: Some(7),
+    });
+}
+
+#[test]
+fn notification() {
+    assert_round_trip(Notification {
+        text: "Door open".to_string(),
+        icon: Some(Icon::Warning),
+        priority: Priority::High,
+        expires_at: time("2024-03-18T10:20:00Z"),
+        generated_at: Some(time("2024-03-18T10:15:00Z")),
+        seq: None,
+    });
+    assert_round_trip(Notification {
+        text: String::new(),
+        icon: None,
+        priority: Priority::Normal,
+        expires_at: time("2024-03-18T10:20:00Z"),
+        generated_at: None,
+        seq: None,
+    });
+}
+
+#[test]
+fn alerts() {
+    assert_round_trip(Alerts {
+        alerts: vec![alert(), alert()],
+        generated_at: Some(time("2024-03-18T10:11:00Z")),
+        seq: Some(3),
+    });
+    assert_round_trip(Alerts::default());
+}
+
+#[test]
+fn alerts_as_list() {
+    let data = serde_json::to_vec(&vec![alert()]).unwrap();
+    let alerts: Alerts = payload::decode(&data).unwrap();
+    assert_eq!(alerts.alerts.len(), 1);
+    assert_eq!(alerts.alerts[0].header_text, "Track works");
+    assert_eq!(alerts.generation().generated_at, None);
+    assert_eq!(alerts.generation().seq, None);
+}
+
+#[test]
+fn optional_fields_omitted() {
+    let tram: Tram = payload::decode(br#"{"departAt": null, "timeLeftMs": 1000}"#).unwrap();
+    assert_eq!(tram.time_left_ms, Some(1000));
+    assert_eq!(tram.seq, None);
+    let notification: Notification =
+        payload::decode(br#"{"text": "Hi", "icon": null, "expiresAt": "2024-03-18T10:20:00Z"}"#)
+            .unwrap();
+    assert_eq!(notification.priority, Priority::Normal);
+}
+
+#[test]
+fn invalid() {
+    assert!(payload::decode::<Tram>(b"").is_err());
+    assert!(payload::decode::<Tram>(b"{\"departAt\": 1}").is_err());
+    assert!(payload::decode::<Alerts>(b"{\"seq\": 1}").is_err());
+    // Truncated, postcard fails instead of defaulting the missing fields
+    let data = postcard(&Alerts {
+        alerts: vec![alert()],
+        generated_at: None,
+        seq: None,
+    });
+    assert!(payload::decode::<Alerts>(&data[..data.len() - 1]).is_err());
+    // The layout from before the generation fields
+    let mut data = postcard(&Alerts::default());
+    data[0] = 0x01;
+    assert!(payload::decode::<Alerts>(&data).is_err());
+}
@@ -0,0 +1,124 @@
+// Timetables as compiled by tramcast-timetable, read the way the firmware does, see
+// docs/timetable.md
+
+#[allow(dead_code)]
+#[path = "../../src/timetable.rs"]
+mod timetable;
+
+use chrono::{DateTime, NaiveDate, Utc};
+use timetable::{Departure, EncodedTimetable, Service, Timetable};
+
+fn days(date: &str) -> i32 {
+    use chrono::Datelike;
+    date.parse::<NaiveDate>().unwrap().num_days_from_ce()
+}
+
+fn time(s: &str) -> DateTime<Utc> {
+    s.parse().unwrap()
+}
+
+// March 2024 in Budapest, UTC+1 until the 31st
+fn timetable() -> Vec<u8> {
+    let timetable = Timetable {
+        timezone: "Europe/Budapest".to_string(),
+        services: vec![
+            // Weekdays
+            Service {
+                weekdays: 0b0011111,
+                start_date: Some(days("2024-03-01")),
+                end_date: Some(days("2024-03-31")),
+                added: vec![],
+                removed: vec![days("2024-03-15")],
+            },
+            // Only on a Sunday
+            Service {
+                weekdays: 0,
+                start_date: None,
+                end_date: None,
+                added: vec![days("2024-03-17")],
+                removed: vec![],
+            },
+        ],
+        tram: vec![
+            // 05:00, 23:50 and 24:20 of the service day
+            Departure {
+                service: 0,
+                time: 5 * 3600,
+            },
+            Departure {
+                service: 0,
+                time: 23 * 3600 + 50 * 60,
+            },
+            Departure {
+                service: 0,
+                time: 24 * 3600 + 20 * 60,
+            },
+            Departure {
+                service: 1,
+                time: 10 * 3600,
+            },
+        ],
+        metro: vec![],
+    };
+    postcard::to_allocvec(&timetable).unwrap()
+}
+
+#[test]
+fn next_departure() {
+    let data = timetable();
+    let timetable = EncodedTimetable::decode(&data).unwrap();
+    assert_eq!(timetable.tram.iter().count(), 4);
+    assert_eq!(timetable.metro.iter().count(), 0);
+
+    let next = |now| timetable.next_departure(&timetable.tram, time(now));
+    // Monday 05:00 local
+    assert_eq!(
+        next("2024-03-18T03:00:00Z"),
+        Some(time("2024-03-18T04:00:00Z"))
+    );
+    assert_eq!(
+        next("2024-03-18T04:00:01Z"),
+        Some(time("2024-03-18T22:50:00Z"))
+    );
+    // After midnight, still on Monday's service day
+    assert_eq!(
+        next("2024-03-18T22:55:00Z"),
+        Some(time("2024-03-18T23:20:00Z"))
+    );
+    // Removed on Friday the 15th, and Saturday has no service
+    assert_eq!(next("2024-03-15T03:00:00Z"), None);
+    // Sunday only has the added one, looked up from Saturday night
+    assert_eq!(
+        next("2024-03-16T23:30:00Z"),
+        Some(time("2024-03-17T09:00:00Z"))
+    );
+    assert_eq!(
+        timetable.next_departure(&timetable.metro, time("2024-03-18T03:00:00Z")),
+        None
+    );
+}
+
+#[test]
+fn empty() {
+    let timetable = EncodedTimetable::default();
+    assert_eq!(timetable.tram.iter().count(), 0);
+    assert_eq!(
+        timetable.next_departure(&timetable.tram, time("2024-03-18T03:00:00Z")),
+        None
+    );
+}
+
+#[test]
+fn invalid() {
+    let data = timetable();
+    assert!(EncodedTimetable::decode(&data[..data.len() - 2]).is_err());
+    assert!(EncodedTimetable::decode(&[]).is_err());
+
+    let unknown_timezone = postcard::to_allocvec(&Timetable {
+        timezone: "Europe/Nowhere".to_string(),
+        ..Timetable::default()
+    })
+    .unwrap();
+    let e = EncodedTimetable::decode(&unknown_timezone).err().unwrap();
+    assert_eq!(e.to_string(), "unknown timezone Europe/Nowhere");
+}
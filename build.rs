@@ -1,9 +1,169 @@
+use std::collections::BTreeMap;
+
 #[derive(serde::Deserialize)]
 struct Config {
     wifi_ssid: String,
     wifi_password: String,
-    mqtt_endpoint: String,
-    mqtt_client_id: String,
+    // Optional when all feeds are polled over HTTP
+    #[serde(default)]
+    mqtt_endpoint: Option<String>,
+    // Required with `mqtt_endpoint`, unique for every device
+    mqtt_client_id: Option<String>,
+    // Tried in order when `mqtt_endpoint` is unreachable
+    #[serde(default)]
+    mqtt_fallback_endpoints: Vec<String>,
+    #[serde(default = "default_mqtt_failover_secs")]
+    mqtt_failover_secs: u64,
+    #[serde(default = "default_mqtt_probe_interval_secs")]
+    mqtt_probe_interval_secs: u64,
+    #[serde(default = "default_mqtt_keepalive_secs")]
+    mqtt_keepalive_secs: u64,
+    #[serde(default = "default_mqtt_persistent_session")]
+    mqtt_persistent_session: bool,
+    // Subscription QoS (0, 1 or 2) by topic, for overriding the defaults
+    #[serde(default)]
+    mqtt_qos: BTreeMap<String, u8>,
+    #[serde(default)]
+    feed_lines: Vec<String>,
+    #[serde(default)]
+    gtfs_tram_stop_ids: Vec<String>,
+    #[serde(default)]
+    gtfs_tram_route_ids: Vec<String>,
+    #[serde(default)]
+    gtfs_metro_stop_ids: Vec<String>,
+    #[serde(default)]
+    gtfs_metro_route_ids: Vec<String>,
+    #[serde(default)]
+    http_feeds: HttpFeeds,
+    // Raw ed25519 public key of `tramcast-sign`, OTA updates and signed commands are
+    // refused without it
+    ota_public_key: Option<String>,
+    // A new OTA image is rolled back unless it passes the health check within this
+    #[serde(default = "default_ota_health_check_secs")]
+    ota_health_check_secs: u64,
+    // Signed commands are refused once they are older than this, see docs/mqtt.md
+    #[serde(default = "default_command_max_age_secs")]
+    command_max_age_secs: u64,
+    // Compiled by `tramcast-timetable`, shown while realtime data is stale
+    timetable: Option<String>,
+    #[serde(default = "default_timetable_stale_secs")]
+    timetable_stale_secs: u64,
+    // Minimum time between saving the state to NVS
+    #[serde(default = "default_persist_interval_secs")]
+    persist_interval_secs: u64,
+    // Saved departures older than this are not restored at startup
+    #[serde(default = "default_persist_max_age_secs")]
+    persist_max_age_secs: u64,
+    // Up to 3 SNTP servers, the ESP-IDF default pool servers are used when empty
+    #[serde(default)]
+    ntp_servers: Vec<String>,
+    // Falls back to the default servers when `ntp_servers` don't answer in time
+    #[serde(default = "default_ntp_timeout_secs")]
+    ntp_timeout_secs: u64,
+    #[serde(default = "default_ntp_resync_secs")]
+    ntp_resync_secs: u64,
+    // The time is reported as unsynced when the last sync is older than this
+    #[serde(default = "default_ntp_max_age_secs")]
+    ntp_max_age_secs: u64,
+    // Changes of the clock larger than this, other than elapsed time, are logged as jumps
+    #[serde(default = "default_clock_jump_secs")]
+    clock_jump_secs: u64,
+    // Broker timestamps trusted for setting the clock while SNTP fails
+    #[serde(default)]
+    broker_time: BrokerTime,
+    // SNTP gets this long after boot or losing the sync before broker timestamps are used
+    #[serde(default = "default_broker_time_after_secs")]
+    broker_time_after_secs: u64,
+}
+
+#[derive(serde::Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+enum BrokerTime {
+    #[default]
+    Off,
+    TimeTopic,
+    // The time topic and the generation timestamps of the feeds
+    Feeds,
+}
+
+#[derive(serde::Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct HttpFeeds {
+    tram: Option<HttpFeed>,
+    metro: Option<HttpFeed>,
+}
+
+#[derive(serde::Deserialize)]
+struct HttpFeed {
+    url: String,
+    #[serde(default = "default_http_interval_secs")]
+    interval_secs: u64,
+    // JSON pointer to the list of departures, the response is a feed payload otherwise
+    #[serde(default)]
+    departures: String,
+    // Fields of a departure holding its UNIX time, the first one present is used
+    #[serde(default)]
+    time_fields: Vec<String>,
+}
+
+fn default_http_interval_secs() -> u64 {
+    30
+}
+
+fn default_timetable_stale_secs() -> u64 {
+    120
+}
+
+fn default_persist_interval_secs() -> u64 {
+    60
+}
+
+fn default_persist_max_age_secs() -> u64 {
+    900
+}
+
+fn default_ntp_timeout_secs() -> u64 {
+    30
+}
+
+fn default_ntp_resync_secs() -> u64 {
+    3600
+}
+
+fn default_ntp_max_age_secs() -> u64 {
+    86400
+}
+
+fn default_clock_jump_secs() -> u64 {
+    5
+}
+
+fn default_broker_time_after_secs() -> u64 {
+    60
+}
+
+fn default_ota_health_check_secs() -> u64 {
+    300
+}
+
+fn default_command_max_age_secs() -> u64 {
+    60
+}
+
+fn default_mqtt_failover_secs() -> u64 {
+    60
+}
+
+fn default_mqtt_probe_interval_secs() -> u64 {
+    300
+}
+
+fn default_mqtt_keepalive_secs() -> u64 {
+    30
+}
+
+fn default_mqtt_persistent_session() -> bool {
+    true
 }
 
 macro_rules! config_entry_to_env {
@@ -12,14 +172,235 @@ macro_rules! config_entry_to_env {
     };
 }
 
+macro_rules! config_list_to_env {
+    ($config:ident, $env:ident, $name:ident) => {
+        println!(
+            "cargo:rustc-env={}={}",
+            stringify!($env),
+            $config.$name.join(",")
+        );
+    };
+}
+
 fn main() {
     embuild::espidf::sysenv::output();
 
     let config_file = std::fs::read_to_string("config.yml").expect("config.yml not found");
     let config: Config = serde_yaml::from_str(&config_file).expect("config.yml is invalid");
 
+    assert!(
+        config.mqtt_endpoint.is_some() || config.mqtt_fallback_endpoints.is_empty(),
+        "config.yml is invalid: mqtt_fallback_endpoints requires mqtt_endpoint"
+    );
+    // A shared client ID would make the displays take over each other's session
+    assert!(
+        config.mqtt_endpoint.is_none() || config.mqtt_client_id.is_some(),
+        "config.yml is invalid: mqtt_endpoint requires mqtt_client_id"
+    );
+    // It is also a level of the topics of the display
+    assert!(
+        config.mqtt_client_id.as_ref().map_or(true, |client_id| {
+            !client_id.is_empty() && !client_id.contains(['/', '+', '#'])
+        }),
+        "config.yml is invalid: mqtt_client_id must not be empty or contain /, + or #"
+    );
+    let mqtt_endpoints = config
+        .mqtt_endpoint
+        .iter()
+        .chain(&config.mqtt_fallback_endpoints)
+        .cloned()
+        .collect::<Vec<_>>();
+    for endpoint in &mqtt_endpoints {
+        let scheme = endpoint.split_once("://").map(|(scheme, _)| scheme);
+        assert!(
+            matches!(scheme, Some("mqtt" | "mqtts" | "ws" | "wss")),
+            "config.yml is invalid: {} must be a mqtt://, mqtts://, ws:// or wss:// URL",
+            endpoint
+        );
+        assert!(
+            !endpoint.contains(','),
+            "config.yml is invalid: {} must not contain commas",
+            endpoint
+        );
+    }
+
+    // CONFIG_LWIP_SNTP_MAX_SERVERS in sdkconfig.defaults
+    assert!(
+        config.ntp_servers.len() <= 3,
+        "config.yml is invalid: at most 3 ntp_servers are supported"
+    );
+    assert!(
+        config
+            .ntp_servers
+            .iter()
+            .all(|server| !server.contains(',')),
+        "config.yml is invalid: ntp_servers must not contain commas"
+    );
+    assert!(
+        config.ntp_max_age_secs > config.ntp_resync_secs,
+        "config.yml is invalid: ntp_max_age_secs must be longer than ntp_resync_secs"
+    );
+
     config_entry_to_env!(config, ESP_WIFI_SSID, wifi_ssid);
     config_entry_to_env!(config, ESP_WIFI_PASS, wifi_password);
-    config_entry_to_env!(config, ESP_MQTT_ENDPOINT, mqtt_endpoint);
-    config_entry_to_env!(config, ESP_MQTT_CLIENT_ID, mqtt_client_id);
+    println!(
+        "cargo:rustc-env=ESP_MQTT_ENDPOINTS={}",
+        mqtt_endpoints.join(",")
+    );
+    config_entry_to_env!(config, ESP_MQTT_FAILOVER_SECS, mqtt_failover_secs);
+    config_entry_to_env!(
+        config,
+        ESP_MQTT_PROBE_INTERVAL_SECS,
+        mqtt_probe_interval_secs
+    );
+    println!(
+        "cargo:rustc-env=ESP_MQTT_CLIENT_ID={}",
+        config.mqtt_client_id.as_deref().unwrap_or_default()
+    );
+    config_entry_to_env!(config, ESP_MQTT_KEEPALIVE_SECS, mqtt_keepalive_secs);
+    config_entry_to_env!(config, ESP_MQTT_PERSISTENT_SESSION, mqtt_persistent_session);
+
+    let mqtt_qos = config
+        .mqtt_qos
+        .iter()
+        .map(|(topic, qos)| {
+            assert!(
+                *qos <= 2,
+                "config.yml is invalid: QoS of {} must be 0, 1 or 2",
+                topic
+            );
+            format!("{}={}", topic, qos)
+        })
+        .collect::<Vec<_>>();
+    println!("cargo:rustc-env=ESP_MQTT_QOS={}", mqtt_qos.join(","));
+
+    config_list_to_env!(config, ESP_FEED_LINES, feed_lines);
+    config_list_to_env!(config, ESP_GTFS_TRAM_STOP_IDS, gtfs_tram_stop_ids);
+    config_list_to_env!(config, ESP_GTFS_TRAM_ROUTE_IDS, gtfs_tram_route_ids);
+    config_list_to_env!(config, ESP_GTFS_METRO_STOP_IDS, gtfs_metro_stop_ids);
+    config_list_to_env!(config, ESP_GTFS_METRO_ROUTE_IDS, gtfs_metro_route_ids);
+
+    http_feed_to_env("TRAM", config.http_feeds.tram.as_ref());
+    http_feed_to_env("METRO", config.http_feeds.metro.as_ref());
+
+    // Embedded with `include_bytes!`, so an empty one is written without a timetable
+    let out_dir = std::env::var("OUT_DIR").unwrap();
+    let timetable = match &config.timetable {
+        Some(path) => {
+            println!("cargo:rerun-if-changed={}", path);
+            std::fs::read(path).expect("timetable not found")
+        }
+        None => Vec::new(),
+    };
+    std::fs::write(format!("{}/timetable.bin", out_dir), timetable).unwrap();
+    // Empty without a key too, OTA updates are rejected then
+    let ota_public_key = match &config.ota_public_key {
+        Some(path) => {
+            println!("cargo:rerun-if-changed={}", path);
+            let key = std::fs::read(path).expect("ota_public_key not found");
+            assert!(
+                key.len() == 32,
+                "config.yml is invalid: ota_public_key must be a raw 32 byte ed25519 key"
+            );
+            key
+        }
+        None => Vec::new(),
+    };
+    std::fs::write(format!("{}/ota_public_key.bin", out_dir), ota_public_key).unwrap();
+    config_entry_to_env!(config, ESP_OTA_HEALTH_CHECK_SECS, ota_health_check_secs);
+    config_entry_to_env!(config, ESP_COMMAND_MAX_AGE_SECS, command_max_age_secs);
+    config_entry_to_env!(config, ESP_TIMETABLE_STALE_SECS, timetable_stale_secs);
+    config_entry_to_env!(config, ESP_PERSIST_INTERVAL_SECS, persist_interval_secs);
+    config_entry_to_env!(config, ESP_PERSIST_MAX_AGE_SECS, persist_max_age_secs);
+    config_list_to_env!(config, ESP_NTP_SERVERS, ntp_servers);
+    config_entry_to_env!(config, ESP_NTP_TIMEOUT_SECS, ntp_timeout_secs);
+    config_entry_to_env!(config, ESP_NTP_RESYNC_SECS, ntp_resync_secs);
+    config_entry_to_env!(config, ESP_NTP_MAX_AGE_SECS, ntp_max_age_secs);
+    config_entry_to_env!(config, ESP_CLOCK_JUMP_SECS, clock_jump_secs);
+    let broker_time = match config.broker_time {
+        BrokerTime::Off => "off",
+        BrokerTime::TimeTopic => "time_topic",
+        BrokerTime::Feeds => "feeds",
+    };
+    println!("cargo:rustc-env=ESP_BROKER_TIME={}", broker_time);
+    config_entry_to_env!(config, ESP_BROKER_TIME_AFTER_SECS, broker_time_after_secs);
+    version_to_env();
+    println!("cargo:rerun-if-changed=config.yml");
+    println!("cargo:rerun-if-changed=build.rs");
+}
+
+// The version of Cargo.toml, with the output of `git describe` as build metadata, e.g.
+// `0.2.0+v0.1.0-5-g1a2b3c4-dirty`. Builds outside a git checkout have none.
+fn version_to_env() {
+    let describe = std::process::Command::new("git")
+        .args(["describe", "--tags", "--always", "--dirty"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+        .unwrap_or_default();
+    // Build metadata may only contain alphanumerics, hyphens and dots
+    let describe: String = describe
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '.' => c,
+            _ => '-',
+        })
+        .collect();
+    let version = std::env::var("CARGO_PKG_VERSION").unwrap();
+    let version = if describe.is_empty() {
+        version
+    } else {
+        format!("{}+{}", version, describe)
+    };
+    let built_at = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+
+    println!("cargo:rustc-env=ESP_VERSION={}", version);
+    println!("cargo:rustc-env=ESP_GIT_DESCRIBE={}", describe);
+    println!("cargo:rustc-env=ESP_BUILT_AT={}", built_at);
+    println!(
+        "cargo:rustc-env=ESP_BUILD_PROFILE={}",
+        std::env::var("PROFILE").unwrap()
+    );
+    // Described again after commits and checkouts, a missing file would rerun every build
+    for path in [".git/HEAD", ".git/index"] {
+        if std::path::Path::new(path).exists() {
+            println!("cargo:rerun-if-changed={}", path);
+        }
+    }
+}
+
+// An empty URL means the feed is not polled
+fn http_feed_to_env(name: &str, feed: Option<&HttpFeed>) {
+    let url = feed.map_or("", |feed| &feed.url);
+    let interval_secs = feed.map_or(0, |feed| feed.interval_secs);
+    let departures = feed.map_or("", |feed| &feed.departures);
+    let time_fields = feed.map_or(String::new(), |feed| feed.time_fields.join(","));
+    assert!(
+        url.is_empty() || url.starts_with("http://") || url.starts_with("https://"),
+        "config.yml is invalid: {} must be a http:// or https:// URL",
+        url
+    );
+    assert!(
+        url.is_empty() || interval_secs > 0,
+        "config.yml is invalid: interval_secs of {} must not be zero",
+        url
+    );
+
+    println!("cargo:rustc-env=ESP_HTTP_{}_URL={}", name, url);
+    println!(
+        "cargo:rustc-env=ESP_HTTP_{}_INTERVAL_SECS={}",
+        name, interval_secs
+    );
+    println!(
+        "cargo:rustc-env=ESP_HTTP_{}_DEPARTURES={}",
+        name, departures
+    );
+    println!(
+        "cargo:rustc-env=ESP_HTTP_{}_TIME_FIELDS={}",
+        name, time_fields
+    );
 }
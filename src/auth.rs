@@ -0,0 +1,88 @@
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::ota;
+
+// Signed commands older than this, or ahead of the clock by this much, are refused
+const COMMAND_MAX_AGE_SECS: &str = env!("ESP_COMMAND_MAX_AGE_SECS");
+// Signed commands are refused while this many recent nonces are remembered
+const MAX_NONCES: usize = 64;
+const MAX_NONCE_LEN: usize = 64;
+
+// Sent along with dangerous commands, signed by the OTA key, see docs/mqtt.md
+#[derive(Deserialize, Debug)]
+struct Auth {
+    // Unique for every command, e.g. random bytes hex encoded
+    nonce: String,
+    // UNIX time the command was signed at
+    timestamp: i64,
+    // Base64 encoded ed25519 signature of `signed_message`
+    signature: String,
+}
+
+// Must match `tramcast-ctl`
+fn signed_message(action: &str, auth: &Auth) -> String {
+    format!(
+        "tramcast-command:{}:{}:{}",
+        auth.timestamp, auth.nonce, action
+    )
+}
+
+// The nonces of the signed commands carried out recently, so a replayed command is refused
+// until its timestamp is too old anyway
+pub struct Nonces {
+    used: Vec<(String, i64)>,
+}
+
+impl Nonces {
+    pub fn new() -> Self {
+        Self { used: Vec::new() }
+    }
+
+    // Whether `auth` signs `action` and wasn't used before. The timestamp can only be
+    // checked once the clock is set, by SNTP or a trusted broker timestamp.
+    pub fn check(
+        &mut self,
+        action: &str,
+        auth: Option<&Value>,
+        clock_set: bool,
+    ) -> Result<(), String> {
+        let auth = Auth::deserialize(auth.ok_or("not signed")?)
+            .map_err(|e| format!("invalid auth: {}", e))?;
+        if auth.nonce.is_empty() || auth.nonce.len() > MAX_NONCE_LEN {
+            return Err("invalid nonce".to_string());
+        }
+        ota::verify_signature(&signed_message(action, &auth), &auth.signature)?;
+
+        if !clock_set {
+            return Err("the clock is not set".to_string());
+        }
+        let now = chrono::Utc::now().timestamp();
+        let max_age: i64 = COMMAND_MAX_AGE_SECS.parse().unwrap();
+        if auth.timestamp < now - max_age {
+            return Err(format!("expired, signed {}s ago", now - auth.timestamp));
+        }
+        if auth.timestamp > now + max_age {
+            return Err(format!(
+                "signed {}s ahead of the clock",
+                auth.timestamp - now
+            ));
+        }
+        // The nonces are forgotten on restart, so older commands could be replayed then
+        let uptime_secs = unsafe { esp_idf_svc::sys::esp_timer_get_time() } / 1_000_000;
+        if auth.timestamp < now - uptime_secs {
+            return Err("signed before the device started".to_string());
+        }
+
+        self.used
+            .retain(|(_, timestamp)| *timestamp >= now - max_age);
+        if self.used.iter().any(|(nonce, _)| *nonce == auth.nonce) {
+            return Err("replayed".to_string());
+        }
+        if self.used.len() >= MAX_NONCES {
+            return Err("too many signed commands, try again later".to_string());
+        }
+        self.used.push((auth.nonce, auth.timestamp));
+        Ok(())
+    }
+}
@@ -0,0 +1,153 @@
+use std::{
+    net::{TcpStream, ToSocketAddrs},
+    time::{Duration, Instant},
+};
+
+// What `Brokers::poll` asks the watchdog to do
+#[derive(Debug, PartialEq, Eq)]
+pub enum Poll {
+    Stay,
+    // The active broker changed, the client has to reconnect
+    Switched,
+    // Whether the primary is reachable again is due to be checked, then reported to
+    // `Brokers::primary_reachable`
+    Probe(&'static str),
+}
+
+// Prioritized list of brokers, the first one being the primary
+pub struct Brokers {
+    endpoints: Vec<&'static str>,
+    active: usize,
+    connected: bool,
+    // When `connected` last changed, or the active broker was switched
+    since: Instant,
+    failover_after: Duration,
+    probe_interval: Duration,
+}
+
+impl Brokers {
+    pub fn new(
+        endpoints: Vec<&'static str>,
+        failover_after: Duration,
+        probe_interval: Duration,
+    ) -> Self {
+        assert!(!endpoints.is_empty(), "No MQTT brokers configured");
+        Self {
+            endpoints,
+            active: 0,
+            connected: false,
+            since: Instant::now(),
+            failover_after,
+            probe_interval,
+        }
+    }
+
+    pub fn active(&self) -> &'static str {
+        self.endpoints[self.active]
+    }
+
+    // Called when a new client is started for the active broker
+    pub fn connecting(&mut self) {
+        self.switch_to(self.active);
+    }
+
+    pub fn set_connected(&mut self, connected: bool) {
+        if self.connected != connected {
+            self.connected = connected;
+            self.since = Instant::now();
+        }
+    }
+
+    pub fn poll(&mut self) -> Poll {
+        if !self.connected && self.endpoints.len() > 1 && self.since.elapsed() > self.failover_after
+        {
+            let failed = self.active();
+            self.switch_to((self.active + 1) % self.endpoints.len());
+            log::warn!(
+                "MQTT broker {} unreachable, failing over to {}",
+                failed,
+                self.active()
+            );
+            return Poll::Switched;
+        }
+
+        if self.connected && self.active != 0 && self.since.elapsed() > self.probe_interval {
+            // Checked again after another interval, unless the primary answers
+            self.since = Instant::now();
+            return Poll::Probe(self.endpoints[0]);
+        }
+
+        Poll::Stay
+    }
+
+    // Returns whether the active broker changed to the primary, which answered a probe
+    pub fn primary_reachable(&mut self) -> bool {
+        // Failed over again, or returned already, while probing
+        if !self.connected || self.active == 0 {
+            return false;
+        }
+        self.switch_to(0);
+        log::info!(
+            "Primary MQTT broker reachable again, returning to {}",
+            self.active()
+        );
+        true
+    }
+
+    fn switch_to(&mut self, index: usize) {
+        self.active = index;
+        self.connected = false;
+        self.since = Instant::now();
+    }
+}
+
+// Whether a TCP connection can be opened to the host of the endpoint URL. Blocks for up to
+// 5 seconds, and resolves the host first.
+pub fn is_reachable(endpoint: &str) -> bool {
+    let Some((host, port)) = address(endpoint) else {
+        return false;
+    };
+    let Ok(mut addrs) = (host, port).to_socket_addrs() else {
+        return false;
+    };
+    addrs
+        .next()
+        .is_some_and(|addr| TcpStream::connect_timeout(&addr, Duration::from_secs(5)).is_ok())
+}
+
+// The host and port of the endpoint URL, IPv6 addresses in brackets, e.g. `[::1]:1883`
+pub fn address(endpoint: &str) -> Option<(&str, u16)> {
+    let (scheme, rest) = endpoint.split_once("://")?;
+    // Strip the path, the query and the credentials
+    let authority = rest.split(['/', '?']).next().unwrap_or(rest);
+    let authority = authority
+        .rsplit_once('@')
+        .map_or(authority, |(_, authority)| authority);
+
+    let (host, port) = match authority.strip_prefix('[') {
+        Some(rest) => {
+            let (host, rest) = rest.split_once(']')?;
+            match rest {
+                "" => (host, None),
+                _ => (host, Some(rest.strip_prefix(':')?)),
+            }
+        }
+        None => match authority.split_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (authority, None),
+        },
+    };
+    if host.is_empty() {
+        return None;
+    }
+    let port = match port {
+        Some(port) => port.parse().ok()?,
+        None => match scheme {
+            "mqtts" => 8883,
+            "ws" => 80,
+            "wss" => 443,
+            _ => 1883,
+        },
+    };
+    Some((host, port))
+}
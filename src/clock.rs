@@ -0,0 +1,234 @@
+use std::{
+    sync::{mpsc::Sender, Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use chrono::{DateTime, Utc};
+use esp_idf_svc::sntp::{EspSntp, SntpConf, SyncStatus};
+
+use crate::state::{StateEvent, TimeSource};
+
+// Retained, carrying the time of the publisher as UNIX seconds or RFC 3339
+pub const TIME_TOPIC: &str = "tramcast/time";
+
+// Comma separated, the ESP-IDF default pool servers are used when empty
+const NTP_SERVERS: &str = env!("ESP_NTP_SERVERS");
+const NTP_TIMEOUT_SECS: &str = env!("ESP_NTP_TIMEOUT_SECS");
+const NTP_RESYNC_SECS: &str = env!("ESP_NTP_RESYNC_SECS");
+const NTP_MAX_AGE_SECS: &str = env!("ESP_NTP_MAX_AGE_SECS");
+const CLOCK_JUMP_SECS: &str = env!("ESP_CLOCK_JUMP_SECS");
+// Which broker timestamps are trusted: `off`, `time_topic` or `feeds`, which includes the topic
+const BROKER_TIME: &str = env!("ESP_BROKER_TIME");
+const BROKER_TIME_AFTER_SECS: &str = env!("ESP_BROKER_TIME_AFTER_SECS");
+
+// Broker timestamps lag behind, by the publishing interval at most for retained messages.
+// Ones behind the clock by less than this confirm a clock set from the broker.
+const BROKER_TIME_MAX_LAG: Duration = Duration::from_secs(300);
+
+pub struct Clock {
+    // Only one SNTP instance may exist at a time, so it is dropped before restarting
+    ntp: Option<EspSntp<'static>>,
+    // The configured servers didn't answer in time, the defaults are used until the next resync
+    fallback: bool,
+    started_at: Instant,
+    synced_at: Option<Instant>,
+    // Set or confirmed by a broker timestamp while SNTP failed
+    broker_time_at: Option<Instant>,
+    created_at: Instant,
+    // Last sent as `StateEvent::TimeSynced`
+    reported: Option<TimeSource>,
+    // Wall clock and monotonic time of the last check, to tell jumps from elapsed time
+    checked_at: (SystemTime, Instant),
+}
+
+impl Clock {
+    pub fn new() -> Self {
+        Self {
+            ntp: None,
+            fallback: false,
+            started_at: Instant::now(),
+            synced_at: None,
+            broker_time_at: None,
+            created_at: Instant::now(),
+            reported: None,
+            checked_at: (SystemTime::now(), Instant::now()),
+        }
+    }
+
+    pub fn is_started(&self) -> bool {
+        self.ntp.is_some()
+    }
+
+    // Whether the clock was synced recently enough to be trusted
+    pub fn is_synced(&self) -> bool {
+        let max_age = Duration::from_secs(NTP_MAX_AGE_SECS.parse().unwrap());
+        self.synced_at.is_some_and(|at| at.elapsed() < max_age)
+    }
+
+    pub fn source(&self) -> Option<TimeSource> {
+        let max_age = Duration::from_secs(NTP_MAX_AGE_SECS.parse().unwrap());
+        if self.is_synced() {
+            Some(TimeSource::Sntp)
+        } else if self.broker_time_at.is_some_and(|at| at.elapsed() < max_age) {
+            Some(TimeSource::Broker)
+        } else {
+            None
+        }
+    }
+
+    // Sets the clock from a timestamp received on `topic`, if the trust policy allows it,
+    // see docs/time.md
+    pub fn set_from_broker(&mut self, time: DateTime<Utc>, topic: &str) {
+        let trusted = match BROKER_TIME {
+            "feeds" => true,
+            "time_topic" => topic == TIME_TOPIC,
+            _ => false,
+        };
+        if !trusted || self.is_synced() {
+            return;
+        }
+        // SNTP gets a chance first, after boot or losing the sync
+        let max_age = Duration::from_secs(NTP_MAX_AGE_SECS.parse().unwrap());
+        let after = Duration::from_secs(BROKER_TIME_AFTER_SECS.parse().unwrap());
+        let unsynced_since = self.synced_at.map_or(self.created_at, |at| at + max_age);
+        if unsynced_since.elapsed() < after {
+            return;
+        }
+
+        let now = Utc::now();
+        // Timestamps are lower bounds, delivery only delays them, so the clock is only moved ahead
+        if time > now {
+            let tv = esp_idf_svc::sys::timeval {
+                tv_sec: time.timestamp() as _,
+                tv_usec: time.timestamp_subsec_micros() as _,
+            };
+            if unsafe { esp_idf_svc::sys::settimeofday(&tv, std::ptr::null()) } != 0 {
+                log::error!("Failed to set the clock from {}", topic);
+                return;
+            }
+            log::info!(
+                "Clock set from {} ahead by {}ms",
+                topic,
+                (time - now).num_milliseconds()
+            );
+            // Not a jump, the clock was set on purpose
+            self.checked_at = (SystemTime::now(), Instant::now());
+            self.broker_time_at = Some(Instant::now());
+        } else if (now - time).to_std().unwrap_or_default() < BROKER_TIME_MAX_LAG {
+            self.broker_time_at = Some(Instant::now());
+        } else {
+            log::debug!("Ignored time from {} behind the clock: {}", topic, time);
+        }
+    }
+
+    // (Re)starts SNTP, which syncs right away. The clock keeps running meanwhile.
+    pub fn start(&mut self) {
+        let servers: Vec<&'static str> = NTP_SERVERS
+            .split(',')
+            .filter(|server| !server.is_empty())
+            .collect();
+        let mut conf = SntpConf::default();
+        if !self.fallback && !servers.is_empty() {
+            // Unused slots repeat the last server, an empty name would be looked up in vain
+            for (i, slot) in conf.servers.iter_mut().enumerate() {
+                *slot = servers[i.min(servers.len() - 1)];
+            }
+        }
+        log::info!("Syncing time from {:?}", conf.servers);
+
+        self.ntp.take();
+        self.ntp = EspSntp::new(&conf)
+            .map_err(|e| log::error!("Failed to start SNTP: {:?}", e))
+            .ok();
+        self.started_at = Instant::now();
+    }
+
+    // Syncs now, trying the configured servers again
+    pub fn resync(&mut self) {
+        self.fallback = false;
+        self.start();
+    }
+
+    fn check(&mut self, tx: &Sender<StateEvent>) {
+        let now = (SystemTime::now(), Instant::now());
+        // Positive when the wall clock moved ahead further than the time elapsed
+        let jump = unix_secs(now.0)
+            - unix_secs(self.checked_at.0)
+            - (now.1 - self.checked_at.1).as_secs_f64();
+        self.checked_at = now;
+        let max_jump: f64 = CLOCK_JUMP_SECS.parse().unwrap();
+
+        // The status is reset once read, so each sync is seen once
+        let synced = self
+            .ntp
+            .as_ref()
+            .is_some_and(|ntp| ntp.get_sync_status() == SyncStatus::Completed);
+        if synced {
+            if self.synced_at.is_some() && jump.abs() > max_jump {
+                log::warn!("Clock was off by {:.1}s, corrected by SNTP", -jump);
+            }
+            log::info!("Time synced");
+            self.synced_at = Some(now.1);
+        } else if self.synced_at.is_some() && jump.abs() > max_jump {
+            log::warn!("Clock jumped by {:.1}s without a sync, resyncing", jump);
+            self.synced_at = None;
+            self.resync();
+        }
+
+        let timeout = Duration::from_secs(NTP_TIMEOUT_SECS.parse().unwrap());
+        let resync_interval = Duration::from_secs(NTP_RESYNC_SECS.parse().unwrap());
+        let waiting = self.synced_at.map_or(true, |at| at < self.started_at);
+        // Started by the MQTT thread once WiFi is up
+        if self.is_started() {
+            if waiting {
+                if !self.fallback && !NTP_SERVERS.is_empty() && self.started_at.elapsed() > timeout
+                {
+                    log::warn!(
+                        "NTP servers {} didn't answer in {}s, falling back to the defaults",
+                        NTP_SERVERS,
+                        timeout.as_secs()
+                    );
+                    self.fallback = true;
+                    self.start();
+                }
+            } else if self.started_at.elapsed() > resync_interval {
+                self.resync();
+            }
+        }
+
+        let source = self.source();
+        if source != self.reported {
+            if source.is_none() {
+                log::warn!("Time is no longer synced");
+            }
+            self.reported = source;
+            tx.send(StateEvent::TimeSynced(source)).unwrap();
+        }
+    }
+}
+
+// UNIX seconds, optionally with a fraction, or RFC 3339
+pub fn parse_time(data: &[u8]) -> Result<DateTime<Utc>, String> {
+    let text = std::str::from_utf8(data).map_err(|e| e.to_string())?.trim();
+    if let Ok(secs) = text.parse::<f64>() {
+        return DateTime::from_timestamp(secs.trunc() as i64, (secs.fract() * 1e9) as u32)
+            .ok_or_else(|| format!("invalid timestamp {}", text));
+    }
+    DateTime::parse_from_rfc3339(text)
+        .map(|time| time.with_timezone(&Utc))
+        .map_err(|e| format!("invalid time {:?}: {}", text, e))
+}
+
+fn unix_secs(time: SystemTime) -> f64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+}
+
+// Reports the sync status to the display, and keeps the clock in sync
+pub fn clock_watchdog(clock: Arc<Mutex<Clock>>, tx: Sender<StateEvent>) -> ! {
+    loop {
+        clock.lock().unwrap().check(&tx);
+        std::thread::sleep(Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,159 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    draw::Screen,
+    ota::Manifest,
+    state::{TimeSource, UpdateStats},
+    version::Build,
+};
+
+// Received by every display, e.g. for finding them with `status`
+pub const COMMAND_TOPIC: &str = "tramcast/command";
+// The topics of this display are under `tramcast/<mqtt_client_id>/`, see docs/mqtt.md
+pub const DEVICE_COMMAND_TOPIC: &str = concat!("tramcast/", env!("ESP_MQTT_CLIENT_ID"), "/command");
+pub const RESPONSE_TOPIC: &str =
+    concat!("tramcast/", env!("ESP_MQTT_CLIENT_ID"), "/command/response");
+
+// Payload example: {"id": "42", "command": "brightness", "value": 128}
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Command {
+    Screen {
+        screen: Screen,
+    },
+    PauseRotation,
+    ResumeRotation,
+    Brightness {
+        value: u8,
+    },
+    Reboot,
+    FactoryReset,
+    ResyncTime,
+    Status,
+    // The last frame shown on the display
+    Screenshot,
+    // Followed by the image on `tramcast/<client ID>/ota/data`, see docs/ota.md
+    OtaBegin(Manifest),
+    // Downloads the image from `url` instead
+    OtaDownload {
+        url: String,
+        #[serde(flatten)]
+        manifest: Manifest,
+    },
+    // Discards the update in progress
+    OtaAbort,
+}
+
+impl Command {
+    // What the signature of a dangerous command covers, `None` if it needn't be signed. See
+    // docs/mqtt.md.
+    pub fn signed_action(&self) -> Option<String> {
+        match self {
+            Self::Reboot => Some("reboot".to_string()),
+            Self::FactoryReset => Some("factory_reset".to_string()),
+            // The manifest covers the image
+            Self::OtaBegin(manifest) => Some(format!("ota_begin:{}", manifest.signed_message())),
+            Self::OtaDownload { url, manifest } => Some(format!(
+                "ota_download:{}:{}",
+                manifest.signed_message(),
+                url
+            )),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Request {
+    // Correlation ID, echoed back as-is in the response
+    pub id: Option<Value>,
+    pub command: Result<Command, String>,
+    // Signature of dangerous commands, checked by `auth::Nonces`
+    pub auth: Option<Value>,
+}
+
+impl Request {
+    pub fn parse(data: &[u8]) -> Self {
+        let value = match serde_json::from_slice::<Value>(data) {
+            Ok(value) => value,
+            Err(e) => {
+                return Self {
+                    id: None,
+                    command: Err(format!("invalid JSON: {}", e)),
+                    auth: None,
+                }
+            }
+        };
+
+        // Read the ID separately, so malformed commands can still be correlated
+        let id = value.get("id").cloned();
+        let auth = value.get("auth").cloned();
+        let command = Command::deserialize(value).map_err(|e| format!("invalid command: {}", e));
+
+        Self { id, command, auth }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Ok,
+    Error,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Response {
+    pub id: Option<Value>,
+    pub result: Outcome,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
+}
+
+impl Response {
+    pub fn new(id: Option<Value>, result: Result<Option<Value>, String>) -> Self {
+        match result {
+            Ok(data) => Self {
+                id,
+                result: Outcome::Ok,
+                error: None,
+                data,
+            },
+            Err(error) => Self {
+                id,
+                result: Outcome::Error,
+                error: Some(error),
+                data: None,
+            },
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct Status {
+    pub client_id: &'static str,
+    pub broker: &'static str,
+    pub version: &'static str,
+    pub build: Build,
+    pub uptime_secs: u64,
+    pub free_heap: u32,
+    pub wifi_connected: bool,
+    pub time_synced: bool,
+    // Also set while the clock runs on broker time
+    pub time_source: Option<TimeSource>,
+    pub tram_updates: UpdateStats,
+    pub metro_updates: UpdateStats,
+    pub alert_updates: UpdateStats,
+    pub notification_updates: UpdateStats,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Screenshot {
+    pub width: usize,
+    pub height: usize,
+    // Base64, rows of `width / 8` bytes, the leftmost pixel in the most significant bit, 1
+    // is lit
+    pub data: String,
+}
@@ -0,0 +1,195 @@
+use miniz_oxide::{
+    inflate::stream::{inflate, InflateState},
+    DataFormat, MZError, MZFlush, MZStatus,
+};
+use serde::{Deserialize, Serialize};
+
+// How an OTA image is sent, see docs/ota.md
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Encoding {
+    #[default]
+    Raw,
+    Zlib,
+    // zlib compressed ops, rebuilding the image from the running one
+    Delta,
+}
+
+// Delta ops start with one of these, followed by u32 little endian fields. Must match
+// `tramcast-sign`.
+// Copies `len` bytes at `offset` of the running image: tag, offset, len
+const OP_COPY: u8 = 0;
+const OP_COPY_LEN: usize = 9;
+// Inserts the `len` bytes that follow: tag, len
+const OP_INSERT: u8 = 1;
+const OP_INSERT_LEN: usize = 5;
+
+const BUF_LEN: usize = 4096;
+
+// Where the decoded image goes
+pub trait Sink {
+    fn write(&mut self, data: &[u8]) -> Result<(), String>;
+}
+
+// The image a delta is applied to
+pub trait Base {
+    fn size(&self) -> usize;
+    fn read(&self, offset: usize, buf: &mut [u8]) -> Result<(), String>;
+}
+
+pub enum Decoder<B> {
+    Raw,
+    Zlib(Inflater),
+    Delta(Inflater, Delta<B>),
+}
+
+impl<B: Base> Decoder<B> {
+    pub fn new(encoding: Encoding, base: B) -> Self {
+        match encoding {
+            Encoding::Raw => Self::Raw,
+            Encoding::Zlib => Self::Zlib(Inflater::new()),
+            Encoding::Delta => Self::Delta(
+                Inflater::new(),
+                Delta {
+                    base,
+                    op: Vec::with_capacity(OP_COPY_LEN),
+                    insert_left: 0,
+                    buf: vec![0; BUF_LEN],
+                },
+            ),
+        }
+    }
+
+    // Decodes the data as it is received
+    pub fn write(&mut self, data: &[u8], sink: &mut impl Sink) -> Result<(), String> {
+        match self {
+            Self::Raw => sink.write(data),
+            Self::Zlib(inflater) => inflater.inflate(data, &mut |data| sink.write(data)),
+            Self::Delta(inflater, delta) => {
+                inflater.inflate(data, &mut |data| delta.apply(data, sink))
+            }
+        }
+    }
+
+    // Whether everything sent was decoded, the stream wasn't cut short
+    pub fn is_done(&self) -> bool {
+        match self {
+            Self::Raw => true,
+            Self::Zlib(inflater) => inflater.ended,
+            Self::Delta(inflater, delta) => {
+                inflater.ended && delta.op.is_empty() && delta.insert_left == 0
+            }
+        }
+    }
+}
+
+pub struct Inflater {
+    // Holds the 32 KiB window, so it is boxed
+    state: Box<InflateState>,
+    buf: Vec<u8>,
+    ended: bool,
+}
+
+impl Inflater {
+    fn new() -> Self {
+        Self {
+            state: InflateState::new_boxed(DataFormat::Zlib),
+            buf: vec![0; BUF_LEN],
+            ended: false,
+        }
+    }
+
+    fn inflate(
+        &mut self,
+        mut input: &[u8],
+        out: &mut impl FnMut(&[u8]) -> Result<(), String>,
+    ) -> Result<(), String> {
+        loop {
+            if self.ended {
+                if !input.is_empty() {
+                    return Err("data after the end of the zlib stream".to_string());
+                }
+                return Ok(());
+            }
+            let result = inflate(&mut self.state, input, &mut self.buf, MZFlush::None);
+            input = &input[result.bytes_consumed..];
+            out(&self.buf[..result.bytes_written])?;
+            match result.status {
+                Ok(MZStatus::StreamEnd) => self.ended = true,
+                Ok(_) => {}
+                // Needs more input
+                Err(MZError::Buf) => return Ok(()),
+                Err(e) => return Err(format!("invalid zlib stream: {:?}", e)),
+            }
+            // Output left over fills the buffer, so it is drained first
+            if input.is_empty() && result.bytes_written < self.buf.len() && !self.ended {
+                return Ok(());
+            }
+        }
+    }
+}
+
+pub struct Delta<B> {
+    base: B,
+    // The op received so far, until its fields are complete
+    op: Vec<u8>,
+    // Of the insert op in progress
+    insert_left: usize,
+    buf: Vec<u8>,
+}
+
+impl<B: Base> Delta<B> {
+    fn apply(&mut self, mut data: &[u8], sink: &mut impl Sink) -> Result<(), String> {
+        while !data.is_empty() {
+            if self.insert_left > 0 {
+                let len = data.len().min(self.insert_left);
+                sink.write(&data[..len])?;
+                self.insert_left -= len;
+                data = &data[len..];
+                continue;
+            }
+
+            self.op.push(data[0]);
+            data = &data[1..];
+            match (self.op[0], self.op.len()) {
+                (OP_COPY, OP_COPY_LEN) => {
+                    let offset = field(&self.op[1..5]);
+                    let len = field(&self.op[5..9]);
+                    self.op.clear();
+                    self.copy(offset, len, sink)?;
+                }
+                (OP_INSERT, OP_INSERT_LEN) => {
+                    self.insert_left = field(&self.op[1..5]);
+                    self.op.clear();
+                }
+                (OP_COPY | OP_INSERT, _) => {}
+                (op, _) => return Err(format!("invalid delta op {}", op)),
+            }
+        }
+        Ok(())
+    }
+
+    fn copy(&mut self, offset: usize, len: usize, sink: &mut impl Sink) -> Result<(), String> {
+        if offset
+            .checked_add(len)
+            .map_or(true, |end| end > self.base.size())
+        {
+            return Err(format!(
+                "delta copies {} bytes at {}, beyond the running image",
+                len, offset
+            ));
+        }
+        let mut copied = 0;
+        while copied < len {
+            let n = (len - copied).min(self.buf.len());
+            self.base.read(offset + copied, &mut self.buf[..n])?;
+            sink.write(&self.buf[..n])?;
+            copied += n;
+        }
+        Ok(())
+    }
+}
+
+fn field(bytes: &[u8]) -> usize {
+    u32::from_le_bytes(bytes.try_into().unwrap()) as usize
+}
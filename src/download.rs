@@ -0,0 +1,174 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::Sender,
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use embedded_svc::{
+    http::{client::Client, Method},
+    io::Read,
+    mqtt::client::Publish,
+};
+use esp_idf_svc::{
+    http::client::{Configuration, EspHttpConnection},
+    nvs::EspDefaultNvsPartition,
+};
+
+use crate::{
+    mqtt,
+    ota::{Chunk, SessionStore, Update},
+    resume::{self, Failure, Retries},
+    state::StateEvent,
+};
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+// Progress is shown and acked this often
+const REPORT_INTERVAL: usize = 16 * 1024;
+
+// Set while the download thread runs, then updates can't be started on `ota::DATA_TOPIC`
+static RUNNING: AtomicBool = AtomicBool::new(false);
+static ABORT: AtomicBool = AtomicBool::new(false);
+
+pub fn is_running() -> bool {
+    RUNNING.load(Ordering::Relaxed)
+}
+
+// Stops the download in progress, false if there is none
+pub fn abort() -> bool {
+    let running = is_running();
+    if running {
+        ABORT.store(true, Ordering::Relaxed);
+    }
+    running
+}
+
+// Downloads the image of the update from `url` in a thread, see docs/ota.md
+pub fn start<C: Publish + Send + 'static>(
+    update: Update,
+    url: String,
+    nvs: EspDefaultNvsPartition,
+    client: Arc<Mutex<Option<C>>>,
+    tx: Sender<StateEvent>,
+) {
+    RUNNING.store(true, Ordering::Relaxed);
+    ABORT.store(false, Ordering::Relaxed);
+    std::thread::Builder::new()
+        .stack_size(8192)
+        .spawn(move || {
+            download_thread(update, &url, nvs, &client, &tx);
+            RUNNING.store(false, Ordering::Relaxed);
+        })
+        .unwrap();
+}
+
+fn download_thread<C: Publish>(
+    mut update: Update,
+    url: &str,
+    nvs: EspDefaultNvsPartition,
+    client: &Mutex<Option<C>>,
+    tx: &Sender<StateEvent>,
+) {
+    let mut sessions = SessionStore::new(nvs);
+    let mut retries = Retries::default();
+    loop {
+        let before = update.progress().0;
+        match fetch(&mut update, url, &mut sessions, client, tx) {
+            Ok(()) => {
+                mqtt::finish_ota(update, &mut sessions, client, tx);
+                return;
+            }
+            Err(Failure::Network(e)) => match retries.next_delay(update.progress().0 > before) {
+                Some(delay) => {
+                    log::warn!(
+                        "OTA download failed, retry {}/{} in {:?}: {}",
+                        retries.count(),
+                        resume::RETRIES,
+                        delay,
+                        e
+                    );
+                    std::thread::sleep(delay);
+                }
+                None => {
+                    update.abort(&mut sessions);
+                    mqtt::reject_ota(client, tx, &e);
+                    return;
+                }
+            },
+            Err(Failure::Rejected(e)) => {
+                update.abort(&mut sessions);
+                mqtt::reject_ota(client, tx, &e);
+                return;
+            }
+            Err(Failure::Aborted) => {
+                update.abort(&mut sessions);
+                return;
+            }
+        }
+    }
+}
+
+fn network(e: impl std::fmt::Debug) -> Failure {
+    Failure::Network(format!("{:?}", e))
+}
+
+// Writes the image from where the update stopped, until it is complete
+fn fetch<C: Publish>(
+    update: &mut Update,
+    url: &str,
+    sessions: &mut SessionStore,
+    client: &Mutex<Option<C>>,
+    tx: &Sender<StateEvent>,
+) -> Result<(), Failure> {
+    if ABORT.load(Ordering::Relaxed) {
+        return Err(Failure::Aborted);
+    }
+
+    let connection = EspHttpConnection::new(&Configuration {
+        crt_bundle_attach: Some(esp_idf_svc::sys::esp_crt_bundle_attach),
+        timeout: Some(TIMEOUT),
+        ..Default::default()
+    })
+    .map_err(network)?;
+    let mut http = Client::wrap(connection);
+    let written = update.progress().0;
+    let range = resume::range(written);
+    let headers = [("Range", range.as_str())];
+    let mut response = http
+        .request(Method::Get, url, &headers)
+        .map_err(network)?
+        .submit()
+        .map_err(network)?;
+    // Data before what was written is skipped by `Update::write`
+    let mut offset = resume::body_offset(response.status(), written)?;
+
+    let mut buf = vec![0; 4096];
+    let mut seq = 0;
+    while !update.is_complete() {
+        if ABORT.load(Ordering::Relaxed) {
+            return Err(Failure::Aborted);
+        }
+        let len = response.read(&mut buf).map_err(network)?;
+        if len == 0 {
+            return Err(Failure::Network(format!("connection closed at {}", offset)));
+        }
+        let before = update.progress().0;
+        let ack = update
+            .write(Chunk::new(seq, offset, &buf[..len]), sessions)
+            .map_err(Failure::Rejected)?;
+        offset += len;
+        seq += 1;
+
+        let (written, size) = update.progress();
+        if before / REPORT_INTERVAL != written / REPORT_INTERVAL || update.is_complete() {
+            log::info!("OTA download {}/{}", written, size);
+            tx.send(update.progress_event()).unwrap();
+            if let Some(client) = client.lock().unwrap().as_mut() {
+                mqtt::publish_ota_ack(client, &ack);
+            }
+        }
+    }
+    Ok(())
+}
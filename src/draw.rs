@@ -15,21 +15,49 @@ use embedded_graphics::{
     },
     pixelcolor::BinaryColor,
     prelude::*,
+    primitives::{Circle, PrimitiveStyle, Rectangle, Triangle},
     text::{Alignment, Baseline, Text},
 };
 use esp_idf_svc::hal::gpio::{Gpio16, Gpio17, Gpio21, Gpio22, Gpio23, Gpio25, Gpio26};
 use esp_idf_svc::hal::i2c::I2C0;
 use esp_idf_svc::hal::prelude::*;
 use esp_idf_svc::hal::spi::SPI2;
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
 use ssd1306::{prelude::*, Ssd1306};
 
-use crate::state::{Metro, StateEvent, Tram};
+use crate::{
+    feed::{Alert, Generation, Icon, Metro, Notification, Priority, Severity, Tram},
+    health::HEALTH,
+    persist::{Snapshot, Store},
+    screenshot::Recorder,
+    state::{
+        StateEvent, TimeSource, ALERT_UPDATES, METRO_UPDATES, NOTIFICATION_UPDATES, TRAM_UPDATES,
+    },
+    timetable::{Departures, EncodedTimetable},
+};
+
+// Comma separated lines served by the feeds on this display
+const FEED_LINES: &str = env!("ESP_FEED_LINES");
+// Without a broker, all feeds are polled over HTTP
+const MQTT_ENABLED: bool = !env!("ESP_MQTT_ENDPOINTS").is_empty();
+// Postcard encoded `Timetable`, empty if none is configured
+const TIMETABLE: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/timetable.bin"));
+// Realtime data not updated for this long is replaced by the timetable
+const TIMETABLE_STALE_SECS: &str = env!("ESP_TIMETABLE_STALE_SECS");
+// Updates are only compared with data received this recently, so a publisher restarting its
+// sequence recovers, see docs/mqtt.md#ordering
+const GENERATION_MAX_AGE_SECS: i64 = 300;
+// The state is saved at most this often, to spare the flash
+const PERSIST_INTERVAL_SECS: &str = env!("ESP_PERSIST_INTERVAL_SECS");
+// Saved departures received longer ago than this are not restored
+const PERSIST_MAX_AGE_SECS: &str = env!("ESP_PERSIST_MAX_AGE_SECS");
 
 const NO_WIFI: &[u8] = include_bytes!("../assets/no_wifi.raw");
 const TRAM: &[u8] = include_bytes!("../assets/tram.raw");
 
-type DisplayDevice<DI> =
-    Ssd1306<DI, DisplaySize128x64, ssd1306::mode::BufferedGraphicsMode<DisplaySize128x64>>;
+type DisplayDevice<DI> = Recorder<
+    Ssd1306<DI, DisplaySize128x64, ssd1306::mode::BufferedGraphicsMode<DisplaySize128x64>>,
+>;
 
 const STYLE: MonoTextStyle<'static, BinaryColor> = MonoTextStyleBuilder::new()
     .font(&FONT_6X10)
@@ -59,48 +87,155 @@ const MEDIUM_STYLE: MonoTextStyle<'static, BinaryColor> = MonoTextStyleBuilder::
     .background_color(BinaryColor::Off)
     .build();
 
-enum Screen {
+// Scrolling text moves this many pixels per second
+const SCROLL_SPEED: u32 = 30;
+// Blank space between the end of scrolling text and its next repetition
+const SCROLL_GAP: u32 = 40;
+
+#[derive(serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Screen {
     Tram,
     DataNotAvailable,
     Metro,
     Weather,
+    Notification,
+    Alert,
+    // Only shown during OTA updates
+    #[serde(skip_deserializing)]
+    Ota,
 }
 
+// What `Screen::Ota` shows
+enum OtaState {
+    Progress {
+        version: String,
+        written: usize,
+        size: usize,
+    },
+    Rebooting,
+    Failed(String),
+}
+
+// A failed update is shown for this long
+const OTA_FAILED_SECS: u64 = 10;
+// An update without progress for this long is no longer shown, e.g. when the sender stopped
+const OTA_PROGRESS_TIMEOUT_SECS: u64 = 60;
+
 struct Display<DI> {
     tram: Option<Tram>,
+    tram_received_at: Option<chrono::DateTime<chrono::Utc>>,
     metro: Option<Metro>,
+    metro_received_at: Option<chrono::DateTime<chrono::Utc>>,
+    timetable: EncodedTimetable<'static>,
+    // `TIMETABLE_STALE_SECS`, checked on every frame
+    stale_after: chrono::Duration,
     wifi_connected: bool,
     mqtt_connected: bool,
     time_synced: bool,
+    // `None` while the clock runs, but wasn't synced recently
+    time_source: Option<TimeSource>,
+    notifications: Vec<Notification>,
+    notification_index: usize,
+    alerts: Vec<Alert>,
+    alert_index: usize,
+    alerts_generation: Option<Generation>,
+    alerts_received_at: Option<chrono::DateTime<chrono::Utc>>,
+    ticker_started_at: Instant,
     dev: Option<DisplayDevice<DI>>,
     screen: Screen,
+    screen_shown_at: Instant,
+    last_screen_cycle: Instant,
+    rotation_paused: bool,
+    ota: Option<OtaState>,
+    ota_updated_at: Instant,
+    store: Store,
+    // Restored once the clock is synced
+    pending_snapshot: Option<Snapshot>,
+    state_changed: bool,
+    last_saved: Instant,
 }
 
 impl<DI> Display<DI>
 where
     DI: WriteOnlyDataCommand,
 {
-    fn new(dev: DisplayDevice<DI>) -> Self {
+    fn new(dev: DisplayDevice<DI>, mut store: Store) -> Self {
+        let snapshot = store.load();
         let mut this = Self {
             tram: None,
+            tram_received_at: None,
             metro: None,
+            metro_received_at: None,
+            timetable: load_timetable(),
+            stale_after: chrono::Duration::seconds(TIMETABLE_STALE_SECS.parse().unwrap()),
             wifi_connected: false,
             mqtt_connected: false,
             time_synced: false,
+            time_source: None,
+            notifications: Vec::new(),
+            notification_index: 0,
+            alerts: Vec::new(),
+            alert_index: 0,
+            alerts_generation: None,
+            alerts_received_at: None,
+            ticker_started_at: Instant::now(),
             dev: Some(dev),
             screen: Screen::DataNotAvailable,
+            screen_shown_at: Instant::now(),
+            last_screen_cycle: Instant::now(),
+            rotation_paused: false,
+            ota: None,
+            ota_updated_at: Instant::now(),
+            store,
+            pending_snapshot: None,
+            state_changed: false,
+            last_saved: Instant::now(),
         };
+
+        if let Some(snapshot) = snapshot {
+            // The clock keeps running across soft resets, e.g. after an OTA update.
+            // After a power loss, it starts over and has to be synced first.
+            if chrono::Utc::now() >= snapshot.saved_at {
+                this.time_synced = true;
+                this.restore(snapshot);
+            } else {
+                this.pending_snapshot = Some(snapshot);
+            }
+        }
+
         this.redraw();
         this
     }
 
     fn update_state(&mut self, event: StateEvent) {
+        HEALTH.record(&event);
         match event {
             StateEvent::TramStateChanged(tram) => {
+                let current = current_generation(
+                    self.tram.as_ref().map(Tram::generation),
+                    self.tram_received_at,
+                );
+                if !TRAM_UPDATES.accept(current, tram.generation()) {
+                    log::warn!("Rejected older or duplicate tram update: {:?}", tram);
+                    return;
+                }
                 self.tram = Some(tram);
+                self.tram_received_at = Some(chrono::Utc::now());
+                self.state_changed = true;
             }
             StateEvent::MetroStateChanged(metro) => {
+                let current = current_generation(
+                    self.metro.as_ref().map(Metro::generation),
+                    self.metro_received_at,
+                );
+                if !METRO_UPDATES.accept(current, metro.generation()) {
+                    log::warn!("Rejected older or duplicate metro update: {:?}", metro);
+                    return;
+                }
                 self.metro = Some(metro);
+                self.metro_received_at = Some(chrono::Utc::now());
+                self.state_changed = true;
             }
             StateEvent::WifiConnected(b) => {
                 self.wifi_connected = b;
@@ -108,49 +243,364 @@ where
             StateEvent::MqttConnected(b) => {
                 self.mqtt_connected = b;
             }
-            StateEvent::TimeSynced(b) => {
-                self.time_synced = b;
+            StateEvent::TimeSynced(source) => {
+                // A clock that was set once keeps being shown, marked until it is synced again
+                self.time_source = source;
+                if source.is_some() {
+                    self.time_synced = true;
+                    if let Some(snapshot) = self.pending_snapshot.take() {
+                        self.restore(snapshot);
+                    }
+                }
+            }
+            StateEvent::ShowScreen(screen) => {
+                self.set_screen(screen);
+                self.last_screen_cycle = Instant::now();
+            }
+            StateEvent::RotationPaused(b) => {
+                self.rotation_paused = b;
+            }
+            StateEvent::BrightnessChanged(value) => {
+                let brightness = Brightness::custom(0x2, value);
+                if let Err(e) = self.dev.as_mut().unwrap().set_brightness(brightness) {
+                    log::error!("Failed to set brightness: {:?}", e);
+                }
+            }
+            StateEvent::NotificationReceived(notification) => {
+                // Only compared with the shown one of the same text, which it replaces. With
+                // other content, an equal generation isn't a duplicate.
+                let current = self
+                    .notifications
+                    .iter()
+                    .find(|n| n.text == notification.text)
+                    .filter(|n| **n == notification || n.generation() != notification.generation())
+                    .map(Notification::generation);
+                if !NOTIFICATION_UPDATES.accept(current, notification.generation()) {
+                    log::warn!(
+                        "Rejected older or duplicate notification: {:?}",
+                        notification
+                    );
+                    return;
+                }
+                // A re-published notification replaces the previous one with the same text
+                self.notifications.retain(|n| n.text != notification.text);
+                let priority = notification.priority;
+                self.notifications.push(notification);
+                self.state_changed = true;
+
+                if priority == Priority::High {
+                    self.show_notification(self.notifications.len() - 1);
+                    self.last_screen_cycle = Instant::now();
+                }
+            }
+            StateEvent::AlertsChanged(alerts) => {
+                let current = current_generation(self.alerts_generation, self.alerts_received_at);
+                if !ALERT_UPDATES.accept(current, alerts.generation()) {
+                    log::warn!("Rejected older or duplicate alerts: {:?}", alerts);
+                    return;
+                }
+                self.alerts_generation = Some(alerts.generation());
+                self.alerts_received_at = Some(chrono::Utc::now());
+                self.alerts = alerts.alerts;
+                self.ticker_started_at = Instant::now();
+            }
+            StateEvent::OtaProgress {
+                version,
+                written,
+                size,
+            } => {
+                self.set_ota(OtaState::Progress {
+                    version,
+                    written,
+                    size,
+                });
+            }
+            StateEvent::OtaRebooting => {
+                self.set_ota(OtaState::Rebooting);
+            }
+            StateEvent::OtaFailed(reason) => {
+                self.set_ota(OtaState::Failed(reason));
             }
         }
     }
 
+    // OTA updates preempt the rotation right away
+    fn set_ota(&mut self, ota: OtaState) {
+        self.ota = Some(ota);
+        self.ota_updated_at = Instant::now();
+        self.set_screen(Screen::Ota);
+        self.last_screen_cycle = Instant::now();
+    }
+
+    fn expire_ota(&mut self) {
+        let timeout = match self.ota {
+            Some(OtaState::Progress { .. }) => OTA_PROGRESS_TIMEOUT_SECS,
+            Some(OtaState::Failed(_)) => OTA_FAILED_SECS,
+            Some(OtaState::Rebooting) | None => return,
+        };
+        if self.ota_updated_at.elapsed() > Duration::from_secs(timeout) {
+            self.ota = None;
+        }
+    }
+
     fn event_loop(mut self, rx: Receiver<StateEvent>) -> ! {
-        let mut last_screen_cycle = Instant::now();
+        let persist_interval = Duration::from_secs(PERSIST_INTERVAL_SECS.parse().unwrap());
         loop {
+            HEALTH.draw_loop();
             while let Ok(event) = rx.try_recv() {
                 self.update_state(event);
             }
-            if last_screen_cycle.elapsed() > Duration::from_secs(4) {
+            self.expire_notifications();
+            self.expire_ota();
+            if self.last_screen_cycle.elapsed() > Duration::from_secs(4) {
                 self.cycle_screen();
-                last_screen_cycle = Instant::now();
+                self.last_screen_cycle = Instant::now();
+            }
+            // Timestamps are meaningless until the clock is synced
+            if self.state_changed
+                && self.time_synced
+                && self.last_saved.elapsed() > persist_interval
+            {
+                self.save();
             }
             self.redraw();
             thread::sleep(Duration::from_millis(100));
         }
     }
 
-    fn cycle_screen(&mut self) {
-        if !self.wifi_connected || !self.mqtt_connected || !self.time_synced {
+    fn save(&mut self) {
+        self.store.save(&Snapshot {
+            saved_at: chrono::Utc::now(),
+            tram: self.tram.clone(),
+            tram_received_at: self.tram_received_at,
+            metro: self.metro.clone(),
+            metro_received_at: self.metro_received_at,
+            notifications: self.notifications.clone(),
+        });
+        self.state_changed = false;
+        self.last_saved = Instant::now();
+    }
+
+    // Data received since the boot takes precedence over the saved state
+    fn restore(&mut self, snapshot: Snapshot) {
+        let now = chrono::Utc::now();
+        let max_age = chrono::Duration::seconds(PERSIST_MAX_AGE_SECS.parse().unwrap());
+        // Only departures still ahead, received recently enough to be trusted
+        let is_valid = |depart_at: Option<chrono::DateTime<chrono::Utc>>,
+                        received_at: Option<chrono::DateTime<chrono::Utc>>| {
+            depart_at.is_some_and(|at| at > now)
+                && received_at.is_some_and(|at| now - at <= max_age)
+        };
+
+        let tram_depart_at = snapshot.tram.as_ref().and_then(|tram| tram.depart_at);
+        if self.tram.is_none() && is_valid(tram_depart_at, snapshot.tram_received_at) {
+            self.tram = snapshot.tram;
+            self.tram_received_at = snapshot.tram_received_at;
+        }
+        let metro_depart_at = snapshot.metro.as_ref().and_then(|metro| metro.depart_at);
+        if self.metro.is_none() && is_valid(metro_depart_at, snapshot.metro_received_at) {
+            self.metro = snapshot.metro;
+            self.metro_received_at = snapshot.metro_received_at;
+        }
+        for notification in snapshot.notifications {
+            if notification.expires_at > now
+                && !self
+                    .notifications
+                    .iter()
+                    .any(|n| n.text == notification.text)
+            {
+                self.notifications.push(notification);
+            }
+        }
+
+        log::info!("Restored the state saved at {}", snapshot.saved_at);
+    }
+
+    fn set_screen(&mut self, screen: Screen) {
+        if self.screen != screen {
+            self.screen = screen;
+            self.screen_shown_at = Instant::now();
+        }
+    }
+
+    fn show_notification(&mut self, index: usize) {
+        self.notification_index = index;
+        self.screen = Screen::Notification;
+        self.screen_shown_at = Instant::now();
+    }
+
+    fn show_alert(&mut self, index: usize) {
+        self.alert_index = index;
+        self.screen = Screen::Alert;
+        self.screen_shown_at = Instant::now();
+    }
+
+    // Active alerts affecting the lines of this display, most severe first
+    fn feed_alerts(&self) -> Vec<&Alert> {
+        let now = chrono::Utc::now();
+        let lines = feed_lines();
+        let mut alerts: Vec<&Alert> = self
+            .alerts
+            .iter()
+            .filter(|alert| alert.is_active(now) && alert.affects_any(&lines))
+            .collect();
+        alerts.sort_by_key(|alert| std::cmp::Reverse(alert.severity));
+        alerts
+    }
+
+    // After the tram screen, show the alerts first, then the notifications
+    fn show_alert_or_notification(&mut self, alert_index: usize) {
+        if alert_index < self.feed_alerts().len() {
+            self.show_alert(alert_index);
+        } else if !self.notifications.is_empty() {
+            self.show_notification(0);
+        } else {
+            self.set_screen(Screen::Tram);
+        }
+    }
+
+    fn expire_notifications(&mut self) {
+        let now = chrono::Utc::now();
+        let count = self.notifications.len();
+        self.notifications.retain(|n| n.expires_at > now);
+
+        if self.notifications.len() != count && self.screen == Screen::Notification {
+            // The indices have shifted, restart the rotation from the beginning
             self.screen = Screen::DataNotAvailable;
+            self.cycle_screen();
+            self.last_screen_cycle = Instant::now();
+        }
+    }
+
+    // Whether the text on the current screen has been fully readable at least once
+    fn screen_scrolled(&self) -> bool {
+        match self.screen {
+            Screen::Notification => {
+                let Some(notification) = self.notifications.get(self.notification_index) else {
+                    return true;
+                };
+                let area = notification_text_area(notification);
+                scroll_finished(
+                    &notification.text,
+                    MEDIUM_STYLE,
+                    area.size.width,
+                    self.screen_shown_at,
+                )
+            }
+            Screen::Alert => {
+                let Some(alert) = self.feed_alerts().get(self.alert_index).copied() else {
+                    return true;
+                };
+                scroll_finished(
+                    &alert.header_text,
+                    MEDIUM_STYLE,
+                    ALERT_TEXT_AREA.size.width,
+                    self.screen_shown_at,
+                )
+            }
+            _ => true,
+        }
+    }
+
+    fn cycle_screen(&mut self) {
+        if self.ota.is_some() {
+            self.set_screen(Screen::Ota);
+            return;
+        }
+
+        // High priority notifications preempt the rotation, and take turns if there are many
+        let high_priority: Vec<usize> = (0..self.notifications.len())
+            .filter(|&i| self.notifications[i].priority == Priority::High)
+            .collect();
+        if !high_priority.is_empty() {
+            let showing_high_priority = self.screen == Screen::Notification
+                && high_priority.contains(&self.notification_index);
+            if showing_high_priority && !self.screen_scrolled() {
+                return;
+            }
+            let next = high_priority
+                .iter()
+                .find(|&&i| showing_high_priority && i > self.notification_index)
+                .unwrap_or(&high_priority[0]);
+            self.show_notification(*next);
+            return;
+        }
+
+        // Upcoming departures, restored or from the timetable, keep the display useful
+        // while offline, as long as the clock runs
+        let connected = self.wifi_connected && (!MQTT_ENABLED || self.mqtt_connected);
+        if !self.time_synced || (!connected && !self.has_upcoming_departure()) {
+            self.set_screen(Screen::DataNotAvailable);
+            return;
+        }
+
+        // Stay on the current screen, unless it was only shown because data was missing
+        if self.rotation_paused && self.screen != Screen::DataNotAvailable {
+            return;
+        }
+
+        // Let long texts scroll through at least once before moving on
+        if !self.screen_scrolled() {
             return;
         }
 
         match self.screen {
             Screen::Tram => {
+                self.show_alert_or_notification(0);
                 // TODO: implement other screens
                 //self.screen = Screen::Metro;
             }
             Screen::Metro => {
-                self.screen = Screen::Weather;
+                self.set_screen(Screen::Weather);
             }
             Screen::Weather => {
-                self.screen = Screen::Tram;
+                self.set_screen(Screen::Tram);
             }
-            Screen::DataNotAvailable => {
+            Screen::Notification => {
+                if self.notification_index + 1 < self.notifications.len() {
+                    self.show_notification(self.notification_index + 1);
+                } else {
+                    self.set_screen(Screen::Tram);
+                }
+            }
+            Screen::Alert => {
+                self.show_alert_or_notification(self.alert_index + 1);
+            }
+            Screen::DataNotAvailable | Screen::Ota => {
                 // If all data becomes available, start with the tram screen
-                self.screen = Screen::Tram;
+                self.set_screen(Screen::Tram);
+            }
+        }
+    }
+
+    fn has_upcoming_departure(&self) -> bool {
+        let departure = self.departure(
+            self.tram.as_ref().and_then(|tram| tram.depart_at),
+            self.tram_received_at,
+            &self.timetable.tram,
+        );
+        departure.is_some_and(|(depart_at, _)| depart_at > chrono::Utc::now())
+    }
+
+    // Whether realtime data received at `received_at` is too old to be trusted
+    fn is_stale(&self, received_at: Option<chrono::DateTime<chrono::Utc>>) -> bool {
+        received_at.map_or(true, |at| chrono::Utc::now() - at > self.stale_after)
+    }
+
+    // The next departure and whether it is scheduled, from the timetable if the realtime
+    // data is stale. Stale realtime data is still used when there is no timetable.
+    fn departure(
+        &self,
+        realtime: Option<chrono::DateTime<chrono::Utc>>,
+        received_at: Option<chrono::DateTime<chrono::Utc>>,
+        scheduled: &Departures,
+    ) -> Option<(chrono::DateTime<chrono::Utc>, bool)> {
+        if self.is_stale(received_at) {
+            if let Some(depart_at) = self.timetable.next_departure(scheduled, chrono::Utc::now()) {
+                return Some((depart_at, true));
             }
         }
+        realtime.map(|depart_at| (depart_at, false))
     }
 
     fn redraw(&mut self) {
@@ -160,25 +610,74 @@ where
         self.draw_time();
 
         self.dev.as_mut().unwrap().flush().unwrap();
+        self.dev.as_ref().unwrap().save();
     }
 
     fn draw_screen(&mut self) {
         match self.screen {
             Screen::Tram => {
                 self.draw_tram();
+                self.draw_ticker();
             }
             Screen::Metro => {
                 self.draw_metro();
+                self.draw_ticker();
             }
             Screen::Weather => {
                 self.draw_weather();
             }
+            Screen::Notification => {
+                self.draw_notification();
+            }
+            Screen::Alert => {
+                self.draw_alert();
+            }
             Screen::DataNotAvailable => {
                 self.draw_data_not_available();
             }
+            Screen::Ota => {
+                self.draw_ota();
+            }
         }
     }
 
+    // Every active alert, most severe first, `None` if the ticker is hidden
+    fn ticker_text(&self) -> Option<String> {
+        let now = chrono::Utc::now();
+        let mut alerts: Vec<&Alert> = self.alerts.iter().filter(|a| a.is_active(now)).collect();
+        if alerts.is_empty() {
+            return None;
+        }
+        alerts.sort_by_key(|alert| std::cmp::Reverse(alert.severity));
+
+        let text = alerts
+            .iter()
+            .map(|alert| format!("{}: {}", alert.affected_lines.join(","), alert.header_text))
+            .collect::<Vec<_>>()
+            .join(" | ");
+        Some(text)
+    }
+
+    // Scrolling line at the bottom, listing every active alert
+    fn draw_ticker(&mut self) {
+        let Some(text) = self.ticker_text() else {
+            return;
+        };
+
+        let dev = self.dev.as_mut().unwrap();
+        TICKER_AREA
+            .into_styled(PrimitiveStyle::with_fill(BinaryColor::Off))
+            .draw(dev)
+            .unwrap();
+        draw_scrolling_text(
+            &mut dev.clipped(&TICKER_AREA),
+            &text,
+            STYLE,
+            TICKER_AREA,
+            self.ticker_started_at,
+        );
+    }
+
     fn draw_time(&mut self) {
         if !self.time_synced {
             return;
@@ -198,6 +697,18 @@ where
         Text::with_alignment(&time, top_center, STYLE, Alignment::Center)
             .draw(dev)
             .unwrap();
+
+        let marker = match self.time_source {
+            Some(TimeSource::Sntp) => None,
+            Some(TimeSource::Broker) => Some("B"),
+            None => Some("?"),
+        };
+        if let Some(marker) = marker {
+            let top_right = Point::new(dev.bounding_box().size.width as i32, top_center.y);
+            Text::with_alignment(marker, top_right, STYLE, Alignment::Right)
+                .draw(dev)
+                .unwrap();
+        }
     }
 
     fn draw_tram(&mut self) {
@@ -205,47 +716,63 @@ where
             return;
         }
 
+        let departure = self.departure(
+            self.tram.as_ref().and_then(|tram| tram.depart_at),
+            self.tram_received_at,
+            &self.timetable.tram,
+        );
+        // The icon and the digits reach down to the top of the ticker, lifted while it is
+        // shown so it doesn't cover them
+        let lift = if self.ticker_text().is_some() {
+            Point::new(0, TICKER_LIFT)
+        } else {
+            Point::zero()
+        };
         let dev = self.dev.as_mut().unwrap();
-        let pos = Point::new(4 + 27 / 2, 0) + dev.bounding_box().center().y_axis();
-
-        if let Some(tram) = &self.tram {
-            if let Some(depart_at) = tram.depart_at {
-                let time_left_seconds = depart_at
-                    .round_subsecs(0)
-                    .signed_duration_since(chrono::Utc::now())
-                    .num_seconds();
-
-                if time_left_seconds <= 0 {
-                    Text::with_baseline("now", Point::new(0, 20), STYLE, Baseline::Top)
-                        .draw(dev)
-                        .unwrap();
-                    return;
-                }
-
-                let image_raw: ImageRaw<BinaryColor> = ImageRaw::new(TRAM, 27);
-                let image = Image::with_center(&image_raw, pos);
-                image.draw(dev).unwrap();
+        let pos = Point::new(4 + 27 / 2, 0) + dev.bounding_box().center().y_axis() - lift;
 
-                Text::with_baseline(
-                    &format!("{:02}", time_left_seconds / 60),
-                    Point::new(38, 55),
-                    BIG_STYLE,
-                    Baseline::Bottom,
-                )
-                .draw(dev)
-                .unwrap();
+        if let Some((depart_at, scheduled)) = departure {
+            if scheduled {
+                Text::with_alignment("sched.", Point::new(128, 20), STYLE, Alignment::Right)
+                    .draw(dev)
+                    .unwrap();
+            }
 
-                Text::with_alignment(
-                    &format!(": {:02}", time_left_seconds % 60),
-                    Point::new(128, 47),
-                    MEDIUM_STYLE,
-                    Alignment::Right,
-                )
-                .draw(dev)
-                .unwrap();
+            let time_left_seconds = depart_at
+                .round_subsecs(0)
+                .signed_duration_since(chrono::Utc::now())
+                .num_seconds();
 
+            if time_left_seconds <= 0 {
+                Text::with_baseline("now", Point::new(0, 20), STYLE, Baseline::Top)
+                    .draw(dev)
+                    .unwrap();
                 return;
             }
+
+            let image_raw: ImageRaw<BinaryColor> = ImageRaw::new(TRAM, 27);
+            let image = Image::with_center(&image_raw, pos);
+            image.draw(dev).unwrap();
+
+            Text::with_baseline(
+                &format!("{:02}", time_left_seconds / 60),
+                Point::new(38, 55) - lift,
+                BIG_STYLE,
+                Baseline::Bottom,
+            )
+            .draw(dev)
+            .unwrap();
+
+            Text::with_alignment(
+                &format!(": {:02}", time_left_seconds % 60),
+                Point::new(128, 47) - lift,
+                MEDIUM_STYLE,
+                Alignment::Right,
+            )
+            .draw(dev)
+            .unwrap();
+
+            return;
         }
 
         Text::with_baseline("Tram: N/A", Point::new(0, 20), STYLE, Baseline::Top)
@@ -254,34 +781,41 @@ where
     }
 
     fn draw_metro(&mut self) {
+        let departure = self.departure(
+            self.metro.as_ref().and_then(|metro| metro.depart_at),
+            self.metro_received_at,
+            &self.timetable.metro,
+        );
         let dev = self.dev.as_mut().unwrap();
 
-        if let Some(metro) = &self.metro {
-            if let Some(depart_at) = metro.depart_at {
-                let time_left_seconds = depart_at
-                    .round_subsecs(0)
-                    .signed_duration_since(chrono::Utc::now())
-                    .num_seconds();
-
-                if time_left_seconds <= 0 {
-                    Text::with_baseline("Metro: now", Point::new(0, 20), STYLE, Baseline::Top)
-                        .draw(dev)
-                        .unwrap();
-                    return;
-                }
+        if let Some((depart_at, scheduled)) = departure {
+            let time_left_seconds = depart_at
+                .round_subsecs(0)
+                .signed_duration_since(chrono::Utc::now())
+                .num_seconds();
 
-                let time_left_human =
-                    humantime::format_duration(Duration::from_secs(time_left_seconds as u64));
-
-                Text::with_baseline(
-                    &format!("Metro: {}", time_left_human),
-                    Point::new(0, 20),
-                    STYLE,
-                    Baseline::Top,
-                )
-                .draw(dev)
-                .unwrap();
+            if time_left_seconds <= 0 {
+                Text::with_baseline("Metro: now", Point::new(0, 20), STYLE, Baseline::Top)
+                    .draw(dev)
+                    .unwrap();
+                return;
             }
+
+            let time_left_human =
+                humantime::format_duration(Duration::from_secs(time_left_seconds as u64));
+
+            Text::with_baseline(
+                &format!(
+                    "Metro: {}{}",
+                    time_left_human,
+                    if scheduled { " (sched.)" } else { "" }
+                ),
+                Point::new(0, 20),
+                STYLE,
+                Baseline::Top,
+            )
+            .draw(dev)
+            .unwrap();
         } else {
             Text::with_baseline("Metro: N/A", Point::new(0, 20), STYLE, Baseline::Top)
                 .draw(dev)
@@ -297,6 +831,134 @@ where
             .unwrap();
     }
 
+    fn draw_notification(&mut self) {
+        let dev = self.dev.as_mut().unwrap();
+
+        let Some(notification) = self.notifications.get(self.notification_index) else {
+            Text::with_baseline("No notifications", Point::new(0, 20), STYLE, Baseline::Top)
+                .draw(dev)
+                .unwrap();
+            return;
+        };
+
+        let area = notification_text_area(notification);
+        if let Some(icon) = notification.icon {
+            let center = Point::new(area.top_left.x / 2, area.center().y);
+            draw_icon(dev, icon, center);
+        }
+
+        draw_scrolling_text(
+            &mut dev.clipped(&area),
+            &notification.text,
+            MEDIUM_STYLE,
+            area,
+            self.screen_shown_at,
+        );
+    }
+
+    fn draw_alert(&mut self) {
+        let Some(alert) = self.feed_alerts().get(self.alert_index).copied().cloned() else {
+            return;
+        };
+        let dev = self.dev.as_mut().unwrap();
+
+        let icon = match alert.severity {
+            Severity::Info => Icon::Info,
+            Severity::Warning | Severity::Severe => Icon::Warning,
+        };
+        draw_icon(dev, icon, Point::new(16, 26));
+
+        Text::with_baseline(
+            &alert.affected_lines.join(" "),
+            Point::new(36, 26),
+            MEDIUM_STYLE,
+            Baseline::Middle,
+        )
+        .draw(dev)
+        .unwrap();
+
+        draw_scrolling_text(
+            &mut dev.clipped(&ALERT_TEXT_AREA),
+            &alert.header_text,
+            MEDIUM_STYLE,
+            ALERT_TEXT_AREA,
+            self.screen_shown_at,
+        );
+    }
+
+    fn draw_ota(&mut self) {
+        let Some(ota) = &self.ota else {
+            return;
+        };
+        let dev = self.dev.as_mut().unwrap();
+        let center = dev.bounding_box().center();
+
+        match ota {
+            OtaState::Progress {
+                version,
+                written,
+                size,
+            } => {
+                // The size is checked by the manifest, a zero would only be a bug
+                let (written, size) = (*written, (*size).max(1));
+                Text::with_baseline("Updating to", Point::new(0, 12), STYLE, Baseline::Top)
+                    .draw(dev)
+                    .unwrap();
+                Text::with_baseline(version, Point::new(0, 22), STYLE, Baseline::Top)
+                    .draw(dev)
+                    .unwrap();
+
+                let bar = Rectangle::new(Point::new(4, 34), Size::new(120, 8));
+                bar.into_styled(PrimitiveStyle::with_stroke(BinaryColor::On, 1))
+                    .draw(dev)
+                    .unwrap();
+                let filled = (bar.size.width - 4) as usize * written / size;
+                Rectangle::new(
+                    bar.top_left + Point::new(2, 2),
+                    Size::new(filled as u32, bar.size.height - 4),
+                )
+                .into_styled(PrimitiveStyle::with_fill(BinaryColor::On))
+                .draw(dev)
+                .unwrap();
+
+                let text = format!(
+                    "{}/{} kB {}%",
+                    written / 1024,
+                    size / 1024,
+                    written * 100 / size
+                );
+                Text::with_alignment(&text, Point::new(center.x, 54), STYLE, Alignment::Center)
+                    .draw(dev)
+                    .unwrap();
+            }
+            OtaState::Rebooting => {
+                Text::with_alignment("Update verified", center, STYLE, Alignment::Center)
+                    .draw(dev)
+                    .unwrap();
+                Text::with_alignment(
+                    "Rebooting...",
+                    center + Point::new(0, 12),
+                    STYLE,
+                    Alignment::Center,
+                )
+                .draw(dev)
+                .unwrap();
+            }
+            OtaState::Failed(reason) => {
+                Text::with_alignment("Update failed", center, STYLE, Alignment::Center)
+                    .draw(dev)
+                    .unwrap();
+                draw_scrolling_text(
+                    &mut dev.clipped(&OTA_REASON_AREA),
+                    reason,
+                    STYLE,
+                    OTA_REASON_AREA,
+                    self.ota_updated_at,
+                );
+            }
+        }
+    }
+
     fn draw_data_not_available(&mut self) {
         let dev = self.dev.as_mut().unwrap();
 
@@ -317,7 +979,7 @@ where
             let image_raw: ImageRaw<BinaryColor> = ImageRaw::new(NO_WIFI, 50);
             let image = Image::with_center(&image_raw, center);
             image.draw(dev).unwrap();
-        } else if !self.mqtt_connected {
+        } else if MQTT_ENABLED && !self.mqtt_connected {
             Text::with_alignment(
                 "Connecting MQTT...",
                 bottom_center,
@@ -343,6 +1005,139 @@ where
     }
 }
 
+// Borrows the embedded timetable, its departures are only decoded while looking them up
+// The generation an update of a feed is compared with, none once the current data was received
+// longer than `GENERATION_MAX_AGE_SECS` ago
+fn current_generation(
+    generation: Option<Generation>,
+    received_at: Option<chrono::DateTime<chrono::Utc>>,
+) -> Option<Generation> {
+    let max_age = chrono::Duration::seconds(GENERATION_MAX_AGE_SECS);
+    generation.filter(|_| received_at.is_some_and(|at| chrono::Utc::now() - at <= max_age))
+}
+
+fn load_timetable() -> EncodedTimetable<'static> {
+    if TIMETABLE.is_empty() {
+        return EncodedTimetable::default();
+    }
+    match EncodedTimetable::decode(TIMETABLE) {
+        Ok(timetable) => timetable,
+        Err(e) => {
+            log::error!("Failed to decode the timetable: {:?}", e);
+            EncodedTimetable::default()
+        }
+    }
+}
+
+const TICKER_AREA: Rectangle = Rectangle::new(Point::new(0, 54), Size::new(128, 10));
+// Lifts the tram screen above the ticker. Its icon and the box of its digits end at row 55,
+// the clock above them at row 7.
+const TICKER_LIFT: i32 = 2;
+
+// Below "Update failed"
+const OTA_REASON_AREA: Rectangle = Rectangle::new(Point::new(0, 44), Size::new(128, 12));
+
+// Below the icon and the affected lines
+const ALERT_TEXT_AREA: Rectangle = Rectangle::new(Point::new(0, 42), Size::new(128, 22));
+
+fn feed_lines() -> Vec<&'static str> {
+    FEED_LINES
+        .split(',')
+        .filter(|line| !line.is_empty())
+        .collect()
+}
+
+// Area below the clock, with room for the icon on the left if there is one
+fn notification_text_area(notification: &Notification) -> Rectangle {
+    let left = if notification.icon.is_some() { 32 } else { 0 };
+    Rectangle::new(Point::new(left, 12), Size::new(128 - left as u32, 52))
+}
+
+fn draw_icon<D>(dev: &mut D, icon: Icon, center: Point)
+where
+    D: DrawTarget<Color = BinaryColor>,
+    D::Error: std::fmt::Debug,
+{
+    let stroke = PrimitiveStyle::with_stroke(BinaryColor::On, 2);
+    match icon {
+        Icon::Tram => {
+            let image_raw: ImageRaw<BinaryColor> = ImageRaw::new(TRAM, 27);
+            Image::with_center(&image_raw, center).draw(dev).unwrap();
+        }
+        Icon::Warning => {
+            Triangle::new(
+                center + Point::new(0, -13),
+                center + Point::new(-14, 12),
+                center + Point::new(14, 12),
+            )
+            .into_styled(stroke)
+            .draw(dev)
+            .unwrap();
+            Text::with_alignment("!", center + Point::new(0, 9), STYLE, Alignment::Center)
+                .draw(dev)
+                .unwrap();
+        }
+        Icon::Info => {
+            Circle::with_center(center, 27)
+                .into_styled(stroke)
+                .draw(dev)
+                .unwrap();
+            Text::with_alignment("i", center + Point::new(0, 4), STYLE, Alignment::Center)
+                .draw(dev)
+                .unwrap();
+        }
+    }
+}
+
+fn scroll_width(text: &str, style: MonoTextStyle<'static, BinaryColor>) -> u32 {
+    text.chars().count() as u32 * style.font.character_size.width
+}
+
+fn scrolled_pixels(since: Instant) -> u64 {
+    since.elapsed().as_millis() as u64 * SCROLL_SPEED as u64 / 1000
+}
+
+fn scroll_finished(
+    text: &str,
+    style: MonoTextStyle<'static, BinaryColor>,
+    width: u32,
+    since: Instant,
+) -> bool {
+    let text_width = scroll_width(text, style);
+    text_width <= width || scrolled_pixels(since) >= (text_width + SCROLL_GAP) as u64
+}
+
+// Text that doesn't fit into `area` keeps scrolling to the left, starting at `since`
+fn draw_scrolling_text<D>(
+    dev: &mut D,
+    text: &str,
+    style: MonoTextStyle<'static, BinaryColor>,
+    area: Rectangle,
+    since: Instant,
+) where
+    D: DrawTarget<Color = BinaryColor>,
+    D::Error: std::fmt::Debug,
+{
+    let text_width = scroll_width(text, style);
+    let middle_left = Point::new(area.top_left.x, area.center().y);
+
+    if text_width <= area.size.width {
+        Text::with_baseline(text, middle_left, style, Baseline::Middle)
+            .draw(dev)
+            .unwrap();
+        return;
+    }
+
+    let period = text_width + SCROLL_GAP;
+    let offset = (scrolled_pixels(since) % period as u64) as u32;
+    let start = middle_left - Point::new(offset as i32, 0);
+    for repetition in [start, start + Point::new(period as i32, 0)] {
+        Text::with_baseline(text, repetition, style, Baseline::Middle)
+            .draw(dev)
+            .unwrap();
+    }
+}
+
 #[cfg(not(feature = "simulated"))]
 pub fn draw_thread(
     rx: Receiver<StateEvent>,
@@ -355,6 +1150,7 @@ pub fn draw_thread(
     cs2: Gpio26,
     spi: SPI2,
     _i2c: I2C0,
+    nvs: EspDefaultNvsPartition,
 ) {
     use esp_idf_svc::hal::{
         gpio::PinDriver,
@@ -388,7 +1184,7 @@ pub fn draw_thread(
     .into_buffered_graphics_mode();
     display_device.init().unwrap();
 
-    let display = Display::new(display_device);
+    let display = Display::new(Recorder::new(display_device), Store::new(nvs));
     display.event_loop(rx);
 }
 
@@ -404,6 +1200,7 @@ pub fn draw_thread(
     _cs2: Gpio26,
     _spi: SPI2,
     i2c: I2C0,
+    nvs: EspDefaultNvsPartition,
 ) {
     let config = esp_idf_svc::hal::i2c::I2cConfig::new().baudrate(10.kHz().into());
     let i2c = esp_idf_svc::hal::i2c::I2cDriver::new(i2c, d1, d0, &config).unwrap();
@@ -416,6 +1213,6 @@ pub fn draw_thread(
     .into_buffered_graphics_mode();
     display_device.init().unwrap();
 
-    let display = Display::new(display_device);
+    let display = Display::new(Recorder::new(display_device), Store::new(nvs));
     display.event_loop(rx);
 }
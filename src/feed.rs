@@ -0,0 +1,209 @@
+use std::cmp::Ordering;
+
+use serde::{Deserialize, Deserializer, Serialize};
+
+// Payloads of the feeds, shared with the bridge, see bridge/src/main.rs
+
+// Orders the updates of a feed, so older and duplicate ones can be rejected, see docs/mqtt.md
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Generation {
+    pub generated_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub seq: Option<u64>,
+}
+
+impl Generation {
+    // Only the fields both carry are compared, the timestamp first, then the sequence number.
+    // `None` if they can't be ordered: they share no field, or only equal ones while one of
+    // them carries a field the other doesn't.
+    pub fn compare(&self, other: &Self) -> Option<Ordering> {
+        let shared = [
+            self.generated_at
+                .zip(other.generated_at)
+                .map(|(a, b)| a.cmp(&b)),
+            self.seq.zip(other.seq).map(|(a, b)| a.cmp(&b)),
+        ];
+        if let Some(order) = shared.into_iter().flatten().find(|order| order.is_ne()) {
+            return Some(order);
+        }
+        let same_fields = self.generated_at.is_some() == other.generated_at.is_some()
+            && self.seq.is_some() == other.seq.is_some();
+        let shares_any = shared.iter().any(Option::is_some);
+        (same_fields && shares_any).then_some(Ordering::Equal)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Tram {
+    #[serde(rename = "departAt")]
+    pub depart_at: Option<chrono::DateTime<chrono::Utc>>,
+    #[serde(rename = "timeLeftMs")]
+    pub time_left_ms: Option<i64>,
+    // When the publisher produced the payload
+    #[serde(rename = "generatedAt", default)]
+    pub generated_at: Option<chrono::DateTime<chrono::Utc>>,
+    // Increasing with every payload of the publisher
+    #[serde(default)]
+    pub seq: Option<u64>,
+}
+
+impl Tram {
+    pub fn generation(&self) -> Generation {
+        Generation {
+            generated_at: self.generated_at,
+            seq: self.seq,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Metro {
+    #[serde(rename = "departAt")]
+    pub depart_at: Option<chrono::DateTime<chrono::Utc>>,
+    #[serde(rename = "timeLeftMs")]
+    pub time_left_ms: Option<i64>,
+    #[serde(rename = "generatedAt", default)]
+    pub generated_at: Option<chrono::DateTime<chrono::Utc>>,
+    #[serde(default)]
+    pub seq: Option<u64>,
+}
+
+impl Metro {
+    pub fn generation(&self) -> Generation {
+        Generation {
+            generated_at: self.generated_at,
+            seq: self.seq,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Priority {
+    // Shown as part of the screen rotation
+    #[default]
+    Normal,
+    // Preempts the screen rotation until expired
+    High,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Icon {
+    Tram,
+    Warning,
+    Info,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Notification {
+    pub text: String,
+    pub icon: Option<Icon>,
+    #[serde(default)]
+    pub priority: Priority,
+    #[serde(rename = "expiresAt")]
+    pub expires_at: chrono::DateTime<chrono::Utc>,
+    #[serde(rename = "generatedAt", default)]
+    pub generated_at: Option<chrono::DateTime<chrono::Utc>>,
+    #[serde(default)]
+    pub seq: Option<u64>,
+}
+
+impl Notification {
+    pub fn generation(&self) -> Generation {
+        Generation {
+            generated_at: self.generated_at,
+            seq: self.seq,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    Info,
+    Warning,
+    Severe,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Alert {
+    #[serde(rename = "affectedLines")]
+    pub affected_lines: Vec<String>,
+    pub severity: Severity,
+    #[serde(rename = "headerText")]
+    pub header_text: String,
+    #[serde(rename = "activeFrom")]
+    pub active_from: Option<chrono::DateTime<chrono::Utc>>,
+    #[serde(rename = "activeUntil")]
+    pub active_until: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl Alert {
+    pub fn is_active(&self, now: chrono::DateTime<chrono::Utc>) -> bool {
+        self.active_from.map_or(true, |from| from <= now)
+            && self.active_until.map_or(true, |until| now < until)
+    }
+
+    pub fn affects_any(&self, lines: &[&str]) -> bool {
+        self.affected_lines
+            .iter()
+            .any(|line| lines.contains(&line.as_str()))
+    }
+}
+
+// The complete list of alerts. As JSON, it may also be a plain array, without a generation.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct Alerts {
+    pub alerts: Vec<Alert>,
+    #[serde(rename = "generatedAt")]
+    pub generated_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub seq: Option<u64>,
+}
+
+impl Alerts {
+    pub fn generation(&self) -> Generation {
+        Generation {
+            generated_at: self.generated_at,
+            seq: self.seq,
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Alerts {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        struct Fields {
+            alerts: Vec<Alert>,
+            #[serde(rename = "generatedAt", default)]
+            generated_at: Option<chrono::DateTime<chrono::Utc>>,
+            #[serde(default)]
+            seq: Option<u64>,
+        }
+
+        #[derive(Deserialize)]
+        #[serde(untagged, expecting = "a list of alerts, or an object with `alerts`")]
+        enum Json {
+            List(Vec<Alert>),
+            Fields(Fields),
+        }
+
+        // Postcard isn't self-describing, only JSON can be told apart by its shape
+        let fields = if deserializer.is_human_readable() {
+            match Json::deserialize(deserializer)? {
+                Json::List(alerts) => Fields {
+                    alerts,
+                    generated_at: None,
+                    seq: None,
+                },
+                Json::Fields(fields) => fields,
+            }
+        } else {
+            Fields::deserialize(deserializer)?
+        };
+        Ok(Self {
+            alerts: fields.alerts,
+            generated_at: fields.generated_at,
+            seq: fields.seq,
+        })
+    }
+}
@@ -0,0 +1,146 @@
+use chrono::{DateTime, TimeZone, Utc};
+
+// The subset of gtfs-realtime.proto needed for deriving departures from TripUpdates,
+// see https://gtfs.org/realtime/proto/. Fields not listed here are skipped while decoding.
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct FeedMessage {
+    #[prost(message, required, tag = "1")]
+    pub header: FeedHeader,
+    #[prost(message, repeated, tag = "2")]
+    pub entity: Vec<FeedEntity>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct FeedHeader {
+    #[prost(string, required, tag = "1")]
+    pub gtfs_realtime_version: String,
+    #[prost(uint64, optional, tag = "3")]
+    pub timestamp: Option<u64>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct FeedEntity {
+    #[prost(string, required, tag = "1")]
+    pub id: String,
+    #[prost(bool, optional, tag = "2")]
+    pub is_deleted: Option<bool>,
+    #[prost(message, optional, tag = "3")]
+    pub trip_update: Option<TripUpdate>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct TripUpdate {
+    #[prost(message, required, tag = "1")]
+    pub trip: TripDescriptor,
+    #[prost(message, repeated, tag = "2")]
+    pub stop_time_update: Vec<StopTimeUpdate>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct TripDescriptor {
+    #[prost(string, optional, tag = "1")]
+    pub trip_id: Option<String>,
+    #[prost(enumeration = "TripScheduleRelationship", optional, tag = "4")]
+    pub schedule_relationship: Option<i32>,
+    #[prost(string, optional, tag = "5")]
+    pub route_id: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, prost::Enumeration)]
+pub enum TripScheduleRelationship {
+    Scheduled = 0,
+    Added = 1,
+    Unscheduled = 2,
+    Canceled = 3,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct StopTimeUpdate {
+    #[prost(uint32, optional, tag = "1")]
+    pub stop_sequence: Option<u32>,
+    #[prost(message, optional, tag = "2")]
+    pub arrival: Option<StopTimeEvent>,
+    #[prost(message, optional, tag = "3")]
+    pub departure: Option<StopTimeEvent>,
+    #[prost(string, optional, tag = "4")]
+    pub stop_id: Option<String>,
+    #[prost(enumeration = "StopScheduleRelationship", optional, tag = "5")]
+    pub schedule_relationship: Option<i32>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, prost::Enumeration)]
+pub enum StopScheduleRelationship {
+    Scheduled = 0,
+    Skipped = 1,
+    NoData = 2,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct StopTimeEvent {
+    #[prost(int32, optional, tag = "1")]
+    pub delay: Option<i32>,
+    // POSIX time in seconds
+    #[prost(int64, optional, tag = "2")]
+    pub time: Option<i64>,
+}
+
+// Which trips of the feed belong to a screen of the display
+pub struct StopFilter<'a> {
+    stop_ids: Vec<&'a str>,
+    // Any route, if empty
+    route_ids: Vec<&'a str>,
+}
+
+impl<'a> StopFilter<'a> {
+    // Takes comma separated IDs, returns `None` if there are no stops to filter for
+    pub fn parse(stop_ids: &'a str, route_ids: &'a str) -> Option<Self> {
+        let split = |ids: &'a str| ids.split(',').filter(|id| !id.is_empty()).collect();
+        let filter = Self {
+            stop_ids: split(stop_ids),
+            route_ids: split(route_ids),
+        };
+        (!filter.stop_ids.is_empty()).then_some(filter)
+    }
+
+    fn matches_trip(&self, trip: &TripDescriptor) -> bool {
+        if trip.schedule_relationship() == TripScheduleRelationship::Canceled {
+            return false;
+        }
+        self.route_ids.is_empty()
+            || trip
+                .route_id
+                .as_deref()
+                .is_some_and(|route_id| self.route_ids.contains(&route_id))
+    }
+
+    fn matches_stop(&self, update: &StopTimeUpdate) -> bool {
+        update.schedule_relationship() == StopScheduleRelationship::Scheduled
+            && update
+                .stop_id
+                .as_deref()
+                .is_some_and(|stop_id| self.stop_ids.contains(&stop_id))
+    }
+}
+
+// The earliest departure from the filtered stops that is not in the past.
+// Only absolute times are used, delays would need the static schedule.
+pub fn next_departure(
+    feed: &FeedMessage,
+    filter: &StopFilter,
+    now: DateTime<Utc>,
+) -> Option<DateTime<Utc>> {
+    feed.entity
+        .iter()
+        .filter(|entity| !entity.is_deleted())
+        .filter_map(|entity| entity.trip_update.as_ref())
+        .filter(|trip_update| filter.matches_trip(&trip_update.trip))
+        .flat_map(|trip_update| &trip_update.stop_time_update)
+        .filter(|update| filter.matches_stop(update))
+        .filter_map(|update| {
+            let event = update.departure.as_ref().or(update.arrival.as_ref())?;
+            Utc.timestamp_opt(event.time?, 0).single()
+        })
+        .filter(|time| *time >= now)
+        .min()
+}
@@ -0,0 +1,121 @@
+use std::{
+    sync::atomic::{AtomicBool, AtomicU32, Ordering},
+    time::{Duration, Instant},
+};
+
+use esp_idf_svc::sys::{
+    esp_ota_get_running_partition, esp_ota_get_state_partition,
+    esp_ota_img_states_t_ESP_OTA_IMG_PENDING_VERIFY, ESP_OK,
+};
+
+use crate::state::StateEvent;
+
+const OTA_HEALTH_CHECK_SECS: &str = env!("ESP_OTA_HEALTH_CHECK_SECS");
+const MQTT_ENABLED: bool = !env!("ESP_MQTT_ENDPOINTS").is_empty();
+
+// What the display has seen since boot, the self-test of a new OTA image
+pub struct Health {
+    wifi_connected: AtomicBool,
+    mqtt_connected: AtomicBool,
+    feed_received: AtomicBool,
+    // Incremented by every iteration of the draw loop
+    draw_loops: AtomicU32,
+    // Set once the image is marked valid, until `success` is published
+    unreported: AtomicBool,
+}
+
+pub static HEALTH: Health = Health {
+    wifi_connected: AtomicBool::new(false),
+    mqtt_connected: AtomicBool::new(false),
+    feed_received: AtomicBool::new(false),
+    draw_loops: AtomicU32::new(0),
+    unreported: AtomicBool::new(false),
+};
+
+impl Health {
+    // Called by the draw thread for every event it handles
+    pub fn record(&self, event: &StateEvent) {
+        match event {
+            StateEvent::WifiConnected(b) => self.wifi_connected.store(*b, Ordering::Relaxed),
+            StateEvent::MqttConnected(b) => self.mqtt_connected.store(*b, Ordering::Relaxed),
+            StateEvent::TramStateChanged(_)
+            | StateEvent::MetroStateChanged(_)
+            | StateEvent::AlertsChanged(_)
+            | StateEvent::NotificationReceived(_) => {
+                self.feed_received.store(true, Ordering::Relaxed)
+            }
+            _ => {}
+        }
+    }
+
+    pub fn draw_loop(&self) {
+        self.draw_loops.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Whether `success` is due on `ota::RESULT_TOPIC`, true once after passing
+    pub fn take_report(&self) -> bool {
+        self.unreported.swap(false, Ordering::Relaxed)
+    }
+
+    // What is still missing to pass, `draw_alive` being whether the draw loop ran meanwhile
+    fn missing(&self, draw_alive: bool) -> Vec<&'static str> {
+        [
+            (self.wifi_connected.load(Ordering::Relaxed), "WiFi"),
+            (
+                !MQTT_ENABLED || self.mqtt_connected.load(Ordering::Relaxed),
+                "broker",
+            ),
+            (self.feed_received.load(Ordering::Relaxed), "feed"),
+            (draw_alive, "display"),
+        ]
+        .into_iter()
+        .filter(|(passed, _)| !passed)
+        .map(|(_, check)| check)
+        .collect()
+    }
+}
+
+// The bootloader boots a new image pending verification, and boots the previous one
+// instead if it is reset before the new one is marked valid
+pub fn is_pending_verify() -> bool {
+    let mut state = 0;
+    let result =
+        unsafe { esp_ota_get_state_partition(esp_ota_get_running_partition(), &mut state) };
+    result == ESP_OK && state == esp_ota_img_states_t_ESP_OTA_IMG_PENDING_VERIFY
+}
+
+// Marks a new OTA image valid once it works, and rolls back to the previous one if it
+// doesn't within `ota_health_check_secs`
+pub fn health_check_thread() {
+    if !is_pending_verify() {
+        return;
+    }
+    let window = Duration::from_secs(OTA_HEALTH_CHECK_SECS.parse().unwrap());
+    log::info!("New OTA image, checking its health for {:?}", window);
+
+    let started_at = Instant::now();
+    let mut draw_loops = HEALTH.draw_loops.load(Ordering::Relaxed);
+    loop {
+        std::thread::sleep(Duration::from_secs(1));
+        let previous =
+            std::mem::replace(&mut draw_loops, HEALTH.draw_loops.load(Ordering::Relaxed));
+        let missing = HEALTH.missing(draw_loops != previous);
+        // Confirmed by `ota::CONFIRM_TOPIC` meanwhile
+        if !is_pending_verify() {
+            return;
+        }
+        if missing.is_empty() {
+            log::info!("OTA image passed the health check, marking it valid");
+            esp_ota::mark_app_valid();
+            HEALTH.unreported.store(true, Ordering::Relaxed);
+            return;
+        }
+        if started_at.elapsed() > window {
+            log::error!("OTA image failed the health check, missing: {:?}", missing);
+            if let Err(e) = esp_ota::rollback_and_reboot() {
+                log::error!("Failed to roll back: {:?}", e);
+                return;
+            }
+        }
+    }
+}
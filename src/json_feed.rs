@@ -0,0 +1,28 @@
+use anyhow::bail;
+use chrono::{DateTime, TimeZone, Utc};
+
+// Departures of a JSON API polled over HTTP, shared with the bridge, see docs/http.md
+
+// The earliest departure not in the past, from the list at the JSON pointer `departures`.
+// The first of `time_fields` present in a departure holds its UNIX time.
+pub fn next_departure<S: AsRef<str>>(
+    document: &serde_json::Value,
+    departures: &str,
+    time_fields: &[S],
+    now: DateTime<Utc>,
+) -> anyhow::Result<Option<DateTime<Utc>>> {
+    let Some(list) = document.pointer(departures).and_then(|d| d.as_array()) else {
+        bail!("no list of departures at {}", departures);
+    };
+
+    Ok(list
+        .iter()
+        .filter_map(|departure| {
+            let time = time_fields
+                .iter()
+                .find_map(|field| departure.get(field.as_ref())?.as_i64())?;
+            Utc.timestamp_opt(time, 0).single()
+        })
+        .filter(|time| *time >= now)
+        .min())
+}
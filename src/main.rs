@@ -10,12 +10,40 @@ use esp_idf_svc::{
     timer::EspTaskTimerService,
 };
 
+#[cfg(not(feature = "simulated"))]
+mod auth;
+#[cfg(not(feature = "simulated"))]
+mod broker;
+#[cfg(not(feature = "simulated"))]
+mod clock;
+#[cfg(not(feature = "simulated"))]
+mod command;
+#[cfg(not(feature = "simulated"))]
+mod download;
 mod draw;
+mod feed;
+#[cfg(not(feature = "simulated"))]
+mod gtfs;
+mod health;
+#[cfg(not(feature = "simulated"))]
+mod json_feed;
 #[cfg(not(feature = "simulated"))]
 mod mqtt;
+#[cfg(not(feature = "simulated"))]
+mod ota;
+#[cfg(not(feature = "simulated"))]
+mod payload;
+mod persist;
+#[cfg(not(feature = "simulated"))]
+mod poll;
+#[cfg(not(feature = "simulated"))]
+mod resume;
+mod screenshot;
 #[cfg(feature = "simulated")]
 mod simulated_mqtt;
 mod state;
+mod timetable;
+mod version;
 
 fn main() {
     #[cfg(feature = "simulated")]
@@ -27,6 +55,7 @@ fn main() {
 
     // Bind the log crate to the ESP Logging facilities
     esp_idf_svc::log::EspLogger::initialize_default();
+    log::info!("Running {:?}", version::build());
 
     let peripherals = Peripherals::take().unwrap();
     let sys_loop = EspSystemEventLoop::take().unwrap();
@@ -43,7 +72,15 @@ fn main() {
     let spi2 = peripherals.spi2;
     let i2c0 = peripherals.i2c0;
 
+    // Only runs after booting a new OTA image
+    thread::Builder::new()
+        .stack_size(4096)
+        .spawn(health::health_check_thread)
+        .unwrap();
+
     let (tx, rx) = mpsc::channel::<state::StateEvent>();
+    // Shared with the display, which saves and restores its state there
+    let draw_nvs = nvs.clone();
 
     ThreadSpawnConfiguration {
         name: Some("draw_thread\0".as_bytes()),
@@ -54,7 +91,7 @@ fn main() {
 
     let draw_thread = thread::Builder::new()
         .stack_size(8192)
-        .spawn(move || draw::draw_thread(rx, d0, d1, res, sdi, dc, cs, cs2, spi2, i2c0))
+        .spawn(move || draw::draw_thread(rx, d0, d1, res, sdi, dc, cs, cs2, spi2, i2c0, draw_nvs))
         .unwrap();
 
     ThreadSpawnConfiguration {
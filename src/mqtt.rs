@@ -1,25 +1,98 @@
-use std::sync::mpsc::Sender;
+use std::sync::{mpsc::Sender, Arc, Mutex};
 
+use base64::Engine;
+use chrono::TimeZone;
+use embedded_svc::mqtt::client::Publish;
 use esp_idf_svc::{
     eventloop::EspSystemEventLoop,
-    hal::modem::Modem,
+    hal::{modem::Modem, task::thread::ThreadSpawnConfiguration},
     mqtt::client::{
-        EspMqttClient, InitialChunkData, Message, MessageImpl, MqttClientConfiguration,
-        SubsequentChunkData,
+        Details, EspMqttClient, InitialChunkData, Message, MessageImpl, MqttClientConfiguration,
+        QoS, SubsequentChunkData,
     },
     nvs::EspDefaultNvsPartition,
-    sntp::EspSntp,
     timer::EspTaskTimerService,
     wifi::{AsyncWifi, ClientConfiguration, EspWifi},
 };
 
-use crate::state::{Metro, StateEvent, Tram};
+use crate::{
+    auth::Nonces,
+    broker::{self, Brokers, Poll},
+    clock::{self, Clock},
+    command::{self, Command, Request, Response, Screenshot, Status},
+    download,
+    feed::{Alerts, Metro, Notification, Tram},
+    gtfs::{self, StopFilter},
+    health::HEALTH,
+    ota, payload, poll, screenshot,
+    state::{StateEvent, ALERT_UPDATES, METRO_UPDATES, NOTIFICATION_UPDATES, TRAM_UPDATES},
+    version,
+};
 
 const WIFI_SSID: &str = env!("ESP_WIFI_SSID");
 const WIFI_PASSWORD: &str = env!("ESP_WIFI_PASS");
 
-const MQTT_ENDPOINT: &str = env!("ESP_MQTT_ENDPOINT");
+// Comma separated, in order of priority
+const MQTT_ENDPOINTS: &str = env!("ESP_MQTT_ENDPOINTS");
+const MQTT_FAILOVER_SECS: &str = env!("ESP_MQTT_FAILOVER_SECS");
+const MQTT_PROBE_INTERVAL_SECS: &str = env!("ESP_MQTT_PROBE_INTERVAL_SECS");
 const MQTT_CLIENT_ID: &str = env!("ESP_MQTT_CLIENT_ID");
+const MQTT_KEEPALIVE_SECS: &str = env!("ESP_MQTT_KEEPALIVE_SECS");
+const MQTT_PERSISTENT_SESSION: &str = env!("ESP_MQTT_PERSISTENT_SESSION");
+// Comma separated stop and route IDs of the trips shown from GTFS-Realtime feeds
+const GTFS_TRAM_STOP_IDS: &str = env!("ESP_GTFS_TRAM_STOP_IDS");
+const GTFS_TRAM_ROUTE_IDS: &str = env!("ESP_GTFS_TRAM_ROUTE_IDS");
+const GTFS_METRO_STOP_IDS: &str = env!("ESP_GTFS_METRO_STOP_IDS");
+const GTFS_METRO_ROUTE_IDS: &str = env!("ESP_GTFS_METRO_ROUTE_IDS");
+
+// Comma separated `topic=qos` pairs, overriding the defaults in `SUBSCRIPTIONS`
+const MQTT_QOS: &str = env!("ESP_MQTT_QOS");
+
+// See `docs/mqtt.md` for which of these the publishers are expected to retain
+const SUBSCRIPTIONS: &[(&str, QoS)] = &[
+    ("villamos", QoS::ExactlyOnce),
+    ("metro", QoS::ExactlyOnce),
+    ("alerts", QoS::ExactlyOnce),
+    ("gtfs-realtime", QoS::ExactlyOnce),
+    ("tramcast/notification", QoS::ExactlyOnce),
+    (clock::TIME_TOPIC, QoS::AtMostOnce),
+    (ota::DATA_TOPIC, QoS::ExactlyOnce),
+    (ota::CONFIRM_TOPIC, QoS::ExactlyOnce),
+    (ota::ROLLBACK_TOPIC, QoS::ExactlyOnce),
+    (command::COMMAND_TOPIC, QoS::ExactlyOnce),
+    (command::DEVICE_COMMAND_TOPIC, QoS::ExactlyOnce),
+];
+
+// esp-mqtt picks the transport by the scheme of the endpoint URL: `mqtt://`, `mqtts://`,
+// `ws://` or `wss://`. WebSocket URLs may also have a path, e.g. `wss://example.com/mqtt`.
+fn uses_tls(endpoint: &str) -> bool {
+    endpoint.starts_with("mqtts://") || endpoint.starts_with("wss://")
+}
+
+// Of messages collected from chunks, larger ones are dropped without reserving memory
+const MAX_CHUNKED_MESSAGE_SIZE: usize = 32 * 1024;
+
+// Topics carrying the data shown on the display, see `handle_feed`
+const FEED_TOPICS: &[&str] = &[
+    "villamos",
+    "metro",
+    "alerts",
+    "gtfs-realtime",
+    "tramcast/notification",
+];
+
+fn subscription_qos(topic: &str, default: QoS) -> QoS {
+    MQTT_QOS
+        .split(',')
+        .filter_map(|entry| entry.split_once('='))
+        .find(|(t, _)| *t == topic)
+        .map(|(_, qos)| match qos {
+            "0" => QoS::AtMostOnce,
+            "1" => QoS::AtLeastOnce,
+            _ => QoS::ExactlyOnce,
+        })
+        .unwrap_or(default)
+}
 
 pub async fn mqtt_thread(
     tx: Sender<StateEvent>,
@@ -28,6 +101,17 @@ pub async fn mqtt_thread(
     timer: EspTaskTimerService,
     nvs: EspDefaultNvsPartition,
 ) -> ! {
+    // Downloads open the session store of their own
+    let ota_nvs = nvs.clone();
+    let mut ota_sessions = ota::SessionStore::new(nvs.clone());
+    // An update interrupted by a reboot continues where it was saved, see docs/ota.md
+    let mut ota = ota::Update::resume(&mut ota_sessions);
+    if let Some(update) = &ota {
+        tx.send(update.progress_event()).unwrap();
+    }
+    // Of signed commands, kept across reconnects so they can't be replayed to another broker
+    let mut nonces = Nonces::new();
+
     let mut wifi = AsyncWifi::wrap(
         EspWifi::new(modem, sys_loop.clone(), Some(nvs)).unwrap(),
         sys_loop,
@@ -42,6 +126,52 @@ pub async fn mqtt_thread(
         },
     ))
     .unwrap();
+
+    let endpoints: Vec<&str> = MQTT_ENDPOINTS
+        .split(',')
+        .filter(|endpoint| !endpoint.is_empty())
+        .collect();
+    // No brokers are configured when all feeds are polled over HTTP
+    let brokers = (!endpoints.is_empty()).then(|| {
+        Arc::new(Mutex::new(Brokers::new(
+            endpoints,
+            std::time::Duration::from_secs(MQTT_FAILOVER_SECS.parse().unwrap()),
+            std::time::Duration::from_secs(MQTT_PROBE_INTERVAL_SECS.parse().unwrap()),
+        )))
+    });
+
+    let clock = Arc::new(Mutex::new(Clock::new()));
+    {
+        let clock = clock.clone();
+        let tx = tx.clone();
+        std::thread::Builder::new()
+            .stack_size(4096)
+            .spawn(move || clock::clock_watchdog(clock, tx))
+            .unwrap();
+    }
+    if poll::is_enabled() {
+        ThreadSpawnConfiguration {
+            name: Some("poll_thread\0".as_bytes()),
+            ..Default::default()
+        }
+        .set()
+        .unwrap();
+
+        let clock = clock.clone();
+        let tx = tx.clone();
+        std::thread::Builder::new()
+            .stack_size(8192)
+            .spawn(move || poll::poll_thread(tx, clock))
+            .unwrap();
+
+        ThreadSpawnConfiguration {
+            name: Some("mqtt_thread\0".as_bytes()),
+            ..Default::default()
+        }
+        .set()
+        .unwrap();
+    }
+
     loop {
         if !wifi.is_connected().unwrap() {
             tx.send(StateEvent::WifiConnected(false)).unwrap();
@@ -51,20 +181,57 @@ pub async fn mqtt_thread(
             tx.send(StateEvent::WifiConnected(true)).unwrap();
         }
 
-        let ntp = EspSntp::new_default().unwrap();
-        while ntp.get_sync_status() != esp_idf_svc::sntp::SyncStatus::Completed {
-            std::thread::sleep(std::time::Duration::from_secs(5));
+        // The sync is reported by the clock watchdog, the broker doesn't wait for it
+        {
+            let mut clock = clock.lock().unwrap();
+            if !clock.is_started() {
+                clock.start();
+            }
         }
-        tx.send(StateEvent::TimeSynced(true)).unwrap();
+
+        let Some(brokers) = &brokers else {
+            // Only keep WiFi and the clock up for the poll thread
+            while wifi.is_connected().unwrap() {
+                std::thread::sleep(std::time::Duration::from_secs(10));
+            }
+            continue;
+        };
+
+        let endpoint = {
+            let mut brokers = brokers.lock().unwrap();
+            brokers.connecting();
+            brokers.active()
+        };
+        log::info!("Connecting to MQTT broker {}", endpoint);
 
         let config = MqttClientConfiguration {
             client_id: MQTT_CLIENT_ID.into(),
+            keep_alive_interval: Some(std::time::Duration::from_secs(
+                MQTT_KEEPALIVE_SECS.parse().unwrap(),
+            )),
+            // With a persistent session, the broker queues QoS 1 and 2 messages while offline
+            disable_clean_session: MQTT_PERSISTENT_SESSION.parse().unwrap(),
+            // Verify the broker against the certificates bundled with ESP-IDF
+            crt_bundle_attach: uses_tls(endpoint)
+                .then_some(esp_idf_svc::sys::esp_crt_bundle_attach),
             ..Default::default()
         };
-        let (mut client, mut connection) =
-            EspMqttClient::new_with_conn(MQTT_ENDPOINT, &config).unwrap();
+        let (client, mut connection) = EspMqttClient::new_with_conn(endpoint, &config).unwrap();
+
+        // Shared with the watchdog, which drops it to switch to another broker
+        let client = Arc::new(Mutex::new(Some(client)));
+        {
+            let brokers = brokers.clone();
+            let client = client.clone();
+            std::thread::Builder::new()
+                .stack_size(4096)
+                .spawn(move || failover_watchdog(brokers, client))
+                .unwrap();
+        }
 
-        let mut ota: Option<esp_ota::OtaUpdate> = None;
+        // Topic and data received so far of a message that arrives in chunks, without data
+        // when it is too large and dropped
+        let mut chunked_message: Option<(String, Option<Vec<u8>>)> = None;
 
         #[allow(unreachable_code)]
         while let Some(msg) = connection.next() {
@@ -72,137 +239,210 @@ pub async fn mqtt_thread(
                 Err(e) => log::error!("MQTT Error: {:?}", e),
                 Ok(msg) => {
                     let event: esp_idf_svc::mqtt::client::Event<MessageImpl> = msg;
+                    // A new OTA image that passed the health check is reported once connected
+                    if !matches!(event, esp_idf_svc::mqtt::client::Event::Disconnected)
+                        && HEALTH.take_report()
+                    {
+                        publish_ota_result(&client, "success");
+                    }
 
                     match event {
-                        esp_idf_svc::mqtt::client::Event::Received(msg) => match msg.topic() {
-                            Some("villamos") => {
-                                let payload_raw = String::from_utf8(msg.data().to_vec()).unwrap();
-                                let payload = serde_json::from_str::<Tram>(&payload_raw).unwrap();
-                                log::info!("Payload: {:?}", payload);
-                                tx.send(StateEvent::TramStateChanged(payload)).unwrap();
-                            }
-                            Some("metro") => {
-                                let payload_raw = String::from_utf8(msg.data().to_vec()).unwrap();
-                                let payload = serde_json::from_str::<Metro>(&payload_raw).unwrap();
-                                log::info!("Payload: {:?}", payload);
-                                tx.send(StateEvent::MetroStateChanged(payload)).unwrap();
-                            }
-                            Some("tramcast/ota/data") | None => {
-                                if msg.topic().is_none() && ota.is_none() {
-                                    log::info!(
-                                        "Received unexpected message: id: {:?}, len: {}",
-                                        msg.id(),
-                                        msg.data().len()
-                                    );
-                                    continue;
+                        esp_idf_svc::mqtt::client::Event::Received(msg)
+                            if is_collected_chunk(&msg, chunked_message.is_some()) =>
+                        {
+                            match msg.details() {
+                                Details::InitialChunk(InitialChunkData { total_data_size }) => {
+                                    let topic = msg.topic().unwrap().to_string();
+                                    let data = if *total_data_size > MAX_CHUNKED_MESSAGE_SIZE {
+                                        log::error!(
+                                            "Message of {} bytes on {} dropped, at most {} are supported",
+                                            total_data_size,
+                                            topic,
+                                            MAX_CHUNKED_MESSAGE_SIZE
+                                        );
+                                        None
+                                    } else {
+                                        let mut data = Vec::with_capacity(*total_data_size);
+                                        data.extend_from_slice(msg.data());
+                                        Some(data)
+                                    };
+                                    chunked_message = Some((topic, data));
                                 }
-
-                                let data = msg.data();
-                                if let Some(mut in_progress_ota) = ota.take() {
-                                    match msg.details() {
-                                        esp_idf_svc::mqtt::client::Details::InitialChunk(_) => {
-                                            panic!("Received initial OTA message in middle of OTA");
-                                        }
-                                        esp_idf_svc::mqtt::client::Details::SubsequentChunk(
-                                            SubsequentChunkData {
-                                                current_data_offset,
-                                                total_data_size,
-                                            },
-                                        ) => {
-                                            let current = current_data_offset + data.len();
-                                            log::info!(
-                                                "OTA message {}/{}",
-                                                current,
-                                                total_data_size
-                                            );
-                                            in_progress_ota.write(data).unwrap();
-
-                                            if current == *total_data_size {
-                                                log::info!("OTA message complete, applying...");
-                                                let mut completed_ota =
-                                                    in_progress_ota.finalize().unwrap();
-                                                completed_ota.set_as_boot_partition().unwrap();
-                                                completed_ota.restart();
-                                                log::info!("OTA restart failed");
-                                            } else {
-                                                ota = Some(in_progress_ota);
-                                            }
-                                        }
-                                        esp_idf_svc::mqtt::client::Details::Complete => {
-                                            log::info!("OTA message complete, applying...");
-                                            let mut completed_ota =
-                                                in_progress_ota.finalize().unwrap();
-                                            completed_ota.set_as_boot_partition().unwrap();
-                                            completed_ota.restart();
-                                            log::info!("OTA restart failed");
-                                        }
+                                Details::SubsequentChunk(SubsequentChunkData {
+                                    current_data_offset,
+                                    total_data_size,
+                                }) => {
+                                    let (topic, mut data) = chunked_message.take().unwrap();
+                                    if let Some(data) = &mut data {
+                                        data.extend_from_slice(msg.data());
                                     }
-                                } else {
-                                    log::info!("Starting new OTA update");
-                                    match msg.details() {
-                                        esp_idf_svc::mqtt::client::Details::InitialChunk(
-                                            InitialChunkData { total_data_size },
-                                        ) => {
-                                            log::info!(
-                                                "OTA message (initial) {}/{}",
-                                                data.len(),
-                                                total_data_size
+                                    if current_data_offset + msg.data().len() != *total_data_size {
+                                        chunked_message = Some((topic, data));
+                                    } else if let Some(data) = data {
+                                        if topic == ota::DATA_TOPIC {
+                                            handle_ota_data(
+                                                &data,
+                                                &mut ota,
+                                                &mut ota_sessions,
+                                                &client,
+                                                &tx,
                                             );
-                                            let mut new_ota = esp_ota::OtaUpdate::begin().unwrap();
-                                            new_ota.write(data).unwrap();
-                                            ota = Some(new_ota);
-                                        }
-                                        _ => {
-                                            panic!("Received OTA message without initial chunk");
+                                        } else {
+                                            handle_feed(&topic, &data, &tx, &clock);
                                         }
                                     }
                                 }
+                                Details::Complete => {}
                             }
-                            Some("tramcast/ota/confirm") => {
-                                let msg = String::from_utf8(msg.data().to_vec()).unwrap();
-                                if msg != "success" {
+                        }
+                        esp_idf_svc::mqtt::client::Event::Received(msg) => match msg.topic() {
+                            Some(topic) if FEED_TOPICS.contains(&topic) => {
+                                handle_feed(topic, msg.data(), &tx, &clock);
+                            }
+                            Some(clock::TIME_TOPIC) => match clock::parse_time(msg.data()) {
+                                Ok(time) => clock
+                                    .lock()
+                                    .unwrap()
+                                    .set_from_broker(time, clock::TIME_TOPIC),
+                                Err(e) => log::error!("Invalid time: {}", e),
+                            },
+                            Some(ota::DATA_TOPIC) => {
+                                handle_ota_data(
+                                    msg.data(),
+                                    &mut ota,
+                                    &mut ota_sessions,
+                                    &client,
+                                    &tx,
+                                );
+                            }
+                            Some(ota::CONFIRM_TOPIC) => {
+                                if msg.data() != b"success" {
                                     log::info!(
                                         "Received OTA confirm message with invalid content: {:?}",
-                                        msg
+                                        String::from_utf8_lossy(msg.data())
                                     );
                                     continue;
                                 }
                                 log::info!("Received OTA confirm message");
                                 esp_ota::mark_app_valid();
                             }
-                            Some("tramcast/rollback") => {
+                            Some(ota::ROLLBACK_TOPIC) => {
+                                // The payload is the signature, like `auth` of commands
+                                let auth = serde_json::from_slice(msg.data()).ok();
+                                let clock_set = clock.lock().unwrap().source().is_some();
+                                if let Err(e) = nonces.check("rollback", auth.as_ref(), clock_set) {
+                                    log::warn!("Refused rollback: {}", e);
+                                    continue;
+                                }
                                 log::info!("Received rollback message");
-                                esp_ota::rollback_and_reboot().expect("Failed to rollback");
+                                if let Err(e) = esp_ota::rollback_and_reboot() {
+                                    log::error!("Failed to roll back: {:?}", e);
+                                }
+                            }
+                            Some(command::COMMAND_TOPIC | command::DEVICE_COMMAND_TOPIC) => {
+                                let request = Request::parse(msg.data());
+                                log::info!("Received command: {:?}", request);
+
+                                let result = match &request.command {
+                                    Ok(command) => authorize(
+                                        command,
+                                        request.auth.as_ref(),
+                                        &mut nonces,
+                                        &clock,
+                                    )
+                                    .and_then(|()| {
+                                        run_command(
+                                            command,
+                                            &tx,
+                                            &wifi,
+                                            &clock,
+                                            &mut ota,
+                                            &mut ota_sessions,
+                                            endpoint,
+                                        )
+                                    }),
+                                    Err(e) => Err(e.clone()),
+                                };
+                                match (&request.command, &result) {
+                                    (
+                                        Ok(Command::OtaBegin(_) | Command::OtaDownload { .. }),
+                                        Err(e),
+                                    ) => reject_ota(&client, &tx, e),
+                                    (Ok(Command::OtaDownload { url, .. }), Ok(_)) => {
+                                        // Prepared by `run_command`
+                                        download::start(
+                                            ota.take().unwrap(),
+                                            url.clone(),
+                                            ota_nvs.clone(),
+                                            client.clone(),
+                                            tx.clone(),
+                                        );
+                                    }
+                                    (Ok(Command::OtaAbort), Ok(_)) => {
+                                        publish_ota_result(&client, "aborted")
+                                    }
+                                    _ => {}
+                                }
+                                let succeeded = result.is_ok();
+                                let response = Response::new(request.id, result);
+                                let payload = serde_json::to_vec(&response).unwrap();
+                                if let Some(client) = client.lock().unwrap().as_mut() {
+                                    if let Err(e) = client.publish(
+                                        command::RESPONSE_TOPIC,
+                                        esp_idf_svc::mqtt::client::QoS::AtLeastOnce,
+                                        false,
+                                        &payload,
+                                    ) {
+                                        log::error!("Failed to publish command response: {:?}", e);
+                                    }
+                                }
+
+                                // Device actions that don't return are only carried out
+                                // after the acknowledgement has been sent
+                                match (request.command, succeeded) {
+                                    (Ok(Command::Reboot), true) => {
+                                        std::thread::sleep(std::time::Duration::from_secs(1));
+                                        esp_idf_svc::hal::reset::restart();
+                                    }
+                                    (Ok(Command::FactoryReset), true) => {
+                                        std::thread::sleep(std::time::Duration::from_secs(1));
+                                        esp_idf_svc::sys::esp!(unsafe {
+                                            esp_idf_svc::sys::nvs_flash_erase()
+                                        })
+                                        .expect("Failed to erase NVS");
+                                        esp_idf_svc::hal::reset::restart();
+                                    }
+                                    _ => {}
+                                }
                             }
                             _ => log::info!("Received unknown message: {:?}", msg),
                         },
                         esp_idf_svc::mqtt::client::Event::Connected(_) => {
-                            log::info!("Connected to MQTT broker");
-
-                            let topics = vec![
-                                "villamos",
-                                "metro",
-                                "tramcast/ota/data",
-                                "tramcast/ota/confirm",
-                                "tramcast/rollback",
-                            ];
-                            for topic in topics {
+                            log::info!("Connected to MQTT broker {}", endpoint);
+                            brokers.lock().unwrap().set_connected(true);
+
+                            let mut client = client.lock().unwrap();
+                            let Some(client) = client.as_mut() else {
+                                continue;
+                            };
+
+                            // Retained feed messages are delivered right after subscribing,
+                            // so the screens are populated without waiting for a new publish
+                            for (topic, default_qos) in SUBSCRIPTIONS {
                                 client
-                                    .subscribe(topic, esp_idf_svc::mqtt::client::QoS::ExactlyOnce)
+                                    .subscribe(topic, subscription_qos(topic, *default_qos))
                                     .unwrap();
                             }
-                            client
-                                .publish(
-                                    "tramcast/ota/result",
-                                    esp_idf_svc::mqtt::client::QoS::AtMostOnce,
-                                    false,
-                                    "success".as_bytes(),
-                                )
-                                .unwrap();
+                            // Tells the sender of an interrupted update where to continue
+                            if let Some(update) = &ota {
+                                publish_ota_ack(client, &update.ack());
+                            }
                             tx.send(StateEvent::MqttConnected(true)).unwrap();
                         }
                         esp_idf_svc::mqtt::client::Event::Disconnected => {
-                            log::info!("Disconnected from MQTT broker");
+                            log::info!("Disconnected from MQTT broker {}", endpoint);
+                            chunked_message = None;
+                            brokers.lock().unwrap().set_connected(false);
                             tx.send(StateEvent::MqttConnected(false)).unwrap();
                         }
                         esp_idf_svc::mqtt::client::Event::Subscribed(topic) => {
@@ -216,5 +456,333 @@ pub async fn mqtt_thread(
                 }
             }
         }
+
+        // The watchdog dropped the client, reconnect to the newly active broker
+        tx.send(StateEvent::MqttConnected(false)).unwrap();
+    }
+}
+
+fn publish_ota_result<C: Publish>(client: &Mutex<Option<C>>, result: &str) {
+    if let Some(client) = client.lock().unwrap().as_mut() {
+        if let Err(e) = client.publish(ota::RESULT_TOPIC, QoS::AtMostOnce, false, result.as_bytes())
+        {
+            log::error!("Failed to publish OTA result: {:?}", e);
+        }
+    }
+}
+
+pub fn publish_ota_ack<C: Publish>(client: &mut C, ack: &ota::Ack) {
+    let payload = serde_json::to_vec(ack).unwrap();
+    if let Err(e) = client.publish(ota::ACK_TOPIC, QoS::AtLeastOnce, false, &payload) {
+        log::error!("Failed to publish OTA ack: {:?}", e);
+    }
+}
+
+pub fn reject_ota<C: Publish>(client: &Mutex<Option<C>>, tx: &Sender<StateEvent>, reason: &str) {
+    log::error!("OTA update rejected: {}", reason);
+    tx.send(StateEvent::OtaFailed(reason.to_string())).unwrap();
+    publish_ota_result(client, &format!("rejected: {}", reason));
+}
+
+// Feed messages and OTA chunks larger than the MQTT buffer arrive in chunks, which are
+// collected before handling
+fn is_collected_chunk(msg: &MessageImpl, collecting: bool) -> bool {
+    match msg.details() {
+        Details::InitialChunk(_) => msg
+            .topic()
+            .is_some_and(|topic| FEED_TOPICS.contains(&topic) || topic == ota::DATA_TOPIC),
+        Details::SubsequentChunk(_) => collecting,
+        Details::Complete => false,
+    }
+}
+
+// Writes a chunk of the image announced by `ota_begin`, see docs/ota.md
+fn handle_ota_data<C: Publish>(
+    data: &[u8],
+    ota: &mut Option<ota::Update>,
+    sessions: &mut ota::SessionStore,
+    client: &Mutex<Option<C>>,
+    tx: &Sender<StateEvent>,
+) {
+    // The update is owned by the download thread, so this is not meant for it
+    if download::is_running() {
+        log::warn!("OTA chunk ignored while downloading the image");
+        return;
+    }
+    let Some(mut update) = ota.take() else {
+        reject_ota(client, tx, "no update in progress, send ota_begin first");
+        return;
+    };
+
+    // A malformed chunk is dropped, the ack tells the sender where to continue
+    let chunk = ota::Chunk::parse(data).and_then(|chunk| update.check(&chunk).map(|_| chunk));
+    let chunk = match chunk {
+        Ok(chunk) => chunk,
+        Err(e) => {
+            log::warn!("Invalid OTA chunk dropped: {}", e);
+            if let Some(client) = client.lock().unwrap().as_mut() {
+                publish_ota_ack(client, &update.ack());
+            }
+            *ota = Some(update);
+            return;
+        }
+    };
+    // The data couldn't be decoded or written, so the update can't continue
+    let ack = match update.write(chunk, sessions) {
+        Ok(ack) => ack,
+        Err(e) => {
+            update.abort(sessions);
+            reject_ota(client, tx, &e);
+            return;
+        }
+    };
+    if let Some(client) = client.lock().unwrap().as_mut() {
+        publish_ota_ack(client, &ack);
+    }
+    let (written, size) = update.progress();
+    log::info!("OTA update {}/{}", written, size);
+    tx.send(update.progress_event()).unwrap();
+    if !update.is_complete() {
+        *ota = Some(update);
+        return;
+    }
+
+    finish_ota(update, sessions, client, tx);
+}
+
+// Boots the image once it is verified
+pub fn finish_ota<C: Publish>(
+    update: ota::Update,
+    sessions: &mut ota::SessionStore,
+    client: &Mutex<Option<C>>,
+    tx: &Sender<StateEvent>,
+) {
+    log::info!("OTA image received, verifying...");
+    match update.finish(sessions) {
+        Ok(()) => {
+            log::info!("OTA update verified, restarting");
+            tx.send(StateEvent::OtaRebooting).unwrap();
+            // Long enough for the display to show it
+            std::thread::sleep(std::time::Duration::from_secs(1));
+            esp_idf_svc::hal::reset::restart();
+        }
+        Err(e) => reject_ota(client, tx, &e),
+    }
+}
+
+// With `broker_time: feeds`, the generation timestamps of the feeds may set the clock,
+// before the departures are computed from it
+fn handle_feed(topic: &str, data: &[u8], tx: &Sender<StateEvent>, clock: &Mutex<Clock>) {
+    // Clearing a retained message is delivered as an empty payload
+    if data.is_empty() {
+        log::info!("Retained message cleared: {}", topic);
+        return;
+    }
+
+    match topic {
+        "villamos" => match payload::decode::<Tram>(data) {
+            Ok(payload) => {
+                log::info!("Payload: {:?}", payload);
+                if let Some(generated_at) = payload.generated_at {
+                    clock.lock().unwrap().set_from_broker(generated_at, topic);
+                }
+                tx.send(StateEvent::TramStateChanged(payload)).unwrap();
+            }
+            Err(e) => log::error!("Invalid tram payload: {}", e),
+        },
+        "metro" => match payload::decode::<Metro>(data) {
+            Ok(payload) => {
+                log::info!("Payload: {:?}", payload);
+                if let Some(generated_at) = payload.generated_at {
+                    clock.lock().unwrap().set_from_broker(generated_at, topic);
+                }
+                tx.send(StateEvent::MetroStateChanged(payload)).unwrap();
+            }
+            Err(e) => log::error!("Invalid metro payload: {}", e),
+        },
+        "alerts" => match payload::decode::<Alerts>(data) {
+            Ok(alerts) => {
+                log::info!("Alerts: {:?}", alerts);
+                tx.send(StateEvent::AlertsChanged(alerts)).unwrap();
+            }
+            Err(e) => log::error!("Invalid alerts: {}", e),
+        },
+        "gtfs-realtime" => match <gtfs::FeedMessage as prost::Message>::decode(data) {
+            Ok(feed) => {
+                log::info!("GTFS-Realtime feed with {} entities", feed.entity.len());
+                // The header timestamp orders the feeds, like `generatedAt` of the JSON ones
+                let generated_at = feed
+                    .header
+                    .timestamp
+                    .and_then(|timestamp| i64::try_from(timestamp).ok())
+                    .and_then(|timestamp| chrono::Utc.timestamp_opt(timestamp, 0).single());
+                if let Some(generated_at) = generated_at {
+                    clock.lock().unwrap().set_from_broker(generated_at, topic);
+                }
+                let now = chrono::Utc::now();
+
+                if let Some(filter) = StopFilter::parse(GTFS_TRAM_STOP_IDS, GTFS_TRAM_ROUTE_IDS) {
+                    let depart_at = gtfs::next_departure(&feed, &filter, now);
+                    let time_left_ms = depart_at.map(|at| (at - now).num_milliseconds());
+                    tx.send(StateEvent::TramStateChanged(Tram {
+                        depart_at,
+                        time_left_ms,
+                        generated_at,
+                        seq: None,
+                    }))
+                    .unwrap();
+                }
+                if let Some(filter) = StopFilter::parse(GTFS_METRO_STOP_IDS, GTFS_METRO_ROUTE_IDS) {
+                    let depart_at = gtfs::next_departure(&feed, &filter, now);
+                    let time_left_ms = depart_at.map(|at| (at - now).num_milliseconds());
+                    tx.send(StateEvent::MetroStateChanged(Metro {
+                        depart_at,
+                        time_left_ms,
+                        generated_at,
+                        seq: None,
+                    }))
+                    .unwrap();
+                }
+            }
+            Err(e) => log::error!("Invalid GTFS-Realtime feed: {}", e),
+        },
+        "tramcast/notification" => match payload::decode::<Notification>(data) {
+            Ok(notification) => {
+                log::info!("Notification: {:?}", notification);
+                tx.send(StateEvent::NotificationReceived(notification))
+                    .unwrap();
+            }
+            Err(e) => log::error!("Invalid notification: {}", e),
+        },
+        _ => log::info!("Received message on unknown feed: {}", topic),
+    }
+}
+
+// Drops the client when the active broker changes, which ends its connection loop
+fn failover_watchdog<C: Send>(brokers: Arc<Mutex<Brokers>>, client: Arc<Mutex<Option<C>>>) {
+    while Arc::strong_count(&client) > 1 {
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        let poll = brokers.lock().unwrap().poll();
+        let switched = match poll {
+            Poll::Stay => false,
+            Poll::Switched => true,
+            // Probed without holding the lock, the event loop takes it on every connection
+            // change
+            Poll::Probe(primary) => {
+                broker::is_reachable(primary) && brokers.lock().unwrap().primary_reachable()
+            }
+        };
+        if switched {
+            // Taken out first, so the lock is not held while the client shuts down
+            let client = client.lock().unwrap().take();
+            drop(client);
+            return;
+        }
+    }
+}
+
+// Dangerous commands must be signed, see docs/mqtt.md
+fn authorize(
+    command: &Command,
+    auth: Option<&serde_json::Value>,
+    nonces: &mut Nonces,
+    clock: &Mutex<Clock>,
+) -> Result<(), String> {
+    let Some(action) = command.signed_action() else {
+        return Ok(());
+    };
+    // Broker time counts too, so displays without SNTP can still be updated
+    let clock_set = clock.lock().unwrap().source().is_some();
+    nonces.check(&action, auth, clock_set).map_err(|e| {
+        log::warn!("Refused {}: {}", action, e);
+        format!("unauthorized: {}", e)
+    })
+}
+
+fn run_command(
+    command: &Command,
+    tx: &Sender<StateEvent>,
+    wifi: &AsyncWifi<EspWifi<'static>>,
+    clock: &Mutex<Clock>,
+    ota: &mut Option<ota::Update>,
+    ota_sessions: &mut ota::SessionStore,
+    broker: &'static str,
+) -> Result<Option<serde_json::Value>, String> {
+    match command {
+        Command::Screen { screen } => {
+            tx.send(StateEvent::ShowScreen(*screen)).unwrap();
+        }
+        Command::PauseRotation => {
+            tx.send(StateEvent::RotationPaused(true)).unwrap();
+        }
+        Command::ResumeRotation => {
+            tx.send(StateEvent::RotationPaused(false)).unwrap();
+        }
+        Command::Brightness { value } => {
+            tx.send(StateEvent::BrightnessChanged(*value)).unwrap();
+        }
+        Command::Reboot | Command::FactoryReset => {
+            // Carried out by the caller, once the response is published
+        }
+        Command::OtaBegin(manifest) | Command::OtaDownload { manifest, .. } => {
+            if download::is_running() {
+                return Err("a download is in progress, send ota_abort first".to_string());
+            }
+            // The same manifest again resumes the update, after a disconnect or a reboot
+            if let Some(update) = ota.as_ref().filter(|update| update.is_for(manifest)) {
+                log::info!("OTA update resumed at {}", update.progress().0);
+            } else {
+                if let Some(previous) = ota.take() {
+                    log::warn!("OTA update restarted, aborting the previous one");
+                    previous.abort(ota_sessions);
+                }
+                *ota = Some(ota::Update::begin(manifest.clone(), ota_sessions)?);
+            }
+            let update = ota.as_ref().unwrap();
+            tx.send(update.progress_event()).unwrap();
+            return Ok(Some(serde_json::to_value(update.ack()).unwrap()));
+        }
+        Command::OtaAbort => {
+            // The download thread aborts the update it owns
+            if !download::abort() {
+                let update = ota.take().ok_or("no update in progress")?;
+                update.abort(ota_sessions);
+            }
+            tx.send(StateEvent::OtaFailed("aborted".to_string()))
+                .unwrap();
+        }
+        Command::ResyncTime => {
+            // The clock keeps running meanwhile, so the time is not reported as unsynced
+            clock.lock().unwrap().resync();
+        }
+        Command::Status => {
+            // Locked once, a second guard in the same statement would deadlock
+            let clock = clock.lock().unwrap();
+            let status = Status {
+                client_id: MQTT_CLIENT_ID,
+                broker,
+                version: version::VERSION,
+                build: version::build(),
+                uptime_secs: (unsafe { esp_idf_svc::sys::esp_timer_get_time() } / 1_000_000) as u64,
+                free_heap: unsafe { esp_idf_svc::sys::esp_get_free_heap_size() },
+                wifi_connected: wifi.is_connected().unwrap_or(false),
+                time_synced: clock.is_synced(),
+                time_source: clock.source(),
+                tram_updates: TRAM_UPDATES.stats(),
+                metro_updates: METRO_UPDATES.stats(),
+                alert_updates: ALERT_UPDATES.stats(),
+                notification_updates: NOTIFICATION_UPDATES.stats(),
+            };
+            return Ok(Some(serde_json::to_value(status).unwrap()));
+        }
+        Command::Screenshot => {
+            let screenshot = Screenshot {
+                width: screenshot::WIDTH,
+                height: screenshot::HEIGHT,
+                data: base64::engine::general_purpose::STANDARD.encode(screenshot::frame()),
+            };
+            return Ok(Some(serde_json::to_value(screenshot).unwrap()));
+        }
     }
+    Ok(None)
 }
@@ -0,0 +1,633 @@
+use std::cmp::Ordering;
+
+use base64::Engine;
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use esp_idf_svc::{
+    nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault},
+    sys::{
+        esp, esp_ota_get_next_update_partition, esp_ota_get_running_partition,
+        esp_ota_set_boot_partition, esp_partition_erase_range, esp_partition_read, esp_partition_t,
+        esp_partition_write,
+    },
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    decode::{Base, Decoder, Encoding, Sink},
+    health,
+    state::StateEvent,
+    version,
+};
+
+// Raw ed25519 key the manifests are signed with, empty when OTA updates are not configured
+const PUBLIC_KEY: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/ota_public_key.bin"));
+
+// Like the other OTA topics, only of this display
+pub const RESULT_TOPIC: &str = concat!("tramcast/", env!("ESP_MQTT_CLIENT_ID"), "/ota/result");
+pub const CONFIRM_TOPIC: &str = concat!("tramcast/", env!("ESP_MQTT_CLIENT_ID"), "/ota/confirm");
+// Signed like a command, see docs/mqtt.md
+pub const ROLLBACK_TOPIC: &str = concat!("tramcast/", env!("ESP_MQTT_CLIENT_ID"), "/rollback");
+
+// Magic byte and chip ID of the ESP-IDF app image header
+const IMAGE_MAGIC: u8 = 0xE9;
+const IMAGE_HEADER_LEN: usize = 14;
+
+// Sent with the `ota_begin` command before the image, written by `tramcast-sign`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Manifest {
+    pub size: usize,
+    // Hex encoded SHA-256 digest of the image
+    pub sha256: String,
+    pub version: String,
+    // ESP-IDF target, e.g. `esp32`
+    pub chip: String,
+    // Base64 encoded ed25519 signature of `signed_message`
+    pub signature: String,
+    #[serde(default)]
+    pub encoding: Encoding,
+    // Of the data sent for zlib and delta encoded images
+    #[serde(default)]
+    pub transfer_size: Option<usize>,
+    // Size and hex encoded SHA-256 digest of the running image a delta applies to
+    #[serde(default)]
+    pub base_size: Option<usize>,
+    #[serde(default)]
+    pub base_sha256: Option<String>,
+    // Allows installing an older version
+    #[serde(default)]
+    pub force: bool,
+}
+
+impl Manifest {
+    // Must match `tramcast-sign`
+    pub fn signed_message(&self) -> String {
+        let message = format!(
+            "tramcast-ota:{}:{}:{}:{}",
+            self.size, self.sha256, self.chip, self.version
+        );
+        let message = match self.encoding {
+            Encoding::Raw => message,
+            Encoding::Zlib => format!("{}:zlib:{}", message, self.transfer_size()),
+            Encoding::Delta => format!(
+                "{}:delta:{}:{}:{}",
+                message,
+                self.transfer_size(),
+                self.base_size.unwrap_or_default(),
+                self.base_sha256.as_deref().unwrap_or_default()
+            ),
+        };
+        if self.force {
+            format!("{}:force", message)
+        } else {
+            message
+        }
+    }
+
+    pub fn transfer_size(&self) -> usize {
+        self.transfer_size.unwrap_or(self.size)
+    }
+
+    // The expected digest of the image, if the manifest is signed and meant for this device
+    fn verify(&self, partition_size: usize) -> Result<[u8; 32], String> {
+        verify_signature(&self.signed_message(), &self.signature)?;
+
+        match version::compare(&self.version, version::VERSION) {
+            None => return Err(format!("invalid version {}", self.version)),
+            Some(Ordering::Less) if !self.force => {
+                return Err(format!(
+                    "{} is older than the running {}, set force to downgrade",
+                    self.version,
+                    version::VERSION
+                ));
+            }
+            _ => {}
+        }
+        if self.chip != target() {
+            return Err(format!("image is for {}, not {}", self.chip, target()));
+        }
+        if self.encoding != Encoding::Raw && self.transfer_size.is_none() {
+            return Err("encoded image without transfer_size".to_string());
+        }
+        if self.size < IMAGE_HEADER_LEN || self.size > partition_size {
+            return Err(format!(
+                "invalid size {}, the partition has {} bytes",
+                self.size, partition_size
+            ));
+        }
+        parse_digest(&self.sha256).ok_or_else(|| "invalid sha256".to_string())
+    }
+}
+
+// The image is sent in chunks, each starting with the sequence number and the offset of
+// its data, both u32 little endian
+pub const DATA_TOPIC: &str = concat!("tramcast/", env!("ESP_MQTT_CLIENT_ID"), "/ota/data");
+const CHUNK_HEADER_LEN: usize = 8;
+
+pub struct Chunk<'a> {
+    seq: u32,
+    offset: usize,
+    data: &'a [u8],
+}
+
+impl<'a> Chunk<'a> {
+    pub fn new(seq: u32, offset: usize, data: &'a [u8]) -> Self {
+        Self { seq, offset, data }
+    }
+
+    pub fn parse(data: &'a [u8]) -> Result<Self, String> {
+        if data.len() < CHUNK_HEADER_LEN {
+            return Err(format!(
+                "chunk shorter than its {} byte header",
+                CHUNK_HEADER_LEN
+            ));
+        }
+        let (header, data) = data.split_at(CHUNK_HEADER_LEN);
+        Ok(Self {
+            seq: u32::from_le_bytes(header[..4].try_into().unwrap()),
+            offset: u32::from_le_bytes(header[4..].try_into().unwrap()) as usize,
+            data,
+        })
+    }
+}
+
+// Published for every chunk, `offset` being the bytes received in order, where the sender
+// continues from
+pub const ACK_TOPIC: &str = concat!("tramcast/", env!("ESP_MQTT_CLIENT_ID"), "/ota/ack");
+
+#[derive(Serialize, Debug)]
+pub struct Ack {
+    seq: u32,
+    offset: usize,
+    size: usize,
+}
+
+const SECTOR_SIZE: usize = 4096;
+// The progress is saved this often, to spare the flash. Sector aligned, since a resumed
+// update erases the sector it continues in.
+const RESUME_INTERVAL: usize = 16 * SECTOR_SIZE;
+
+const NVS_NAMESPACE: &str = "tramcast";
+const NVS_KEY: &str = "ota";
+
+// An update in progress, restored to resume it after a reboot. Stored postcard encoded.
+#[derive(Serialize, Deserialize)]
+struct Session {
+    manifest: Manifest,
+    // The resumed update has to write the same partition
+    partition_address: u32,
+    written: usize,
+}
+
+pub struct SessionStore {
+    // `None` if the NVS namespace couldn't be opened, then updates can't be resumed
+    nvs: Option<EspNvs<NvsDefault>>,
+}
+
+impl SessionStore {
+    pub fn new(partition: EspDefaultNvsPartition) -> Self {
+        let nvs = EspNvs::new(partition, NVS_NAMESPACE, true)
+            .map_err(|e| log::error!("Failed to open NVS namespace {}: {:?}", NVS_NAMESPACE, e))
+            .ok();
+        Self { nvs }
+    }
+
+    fn load(&self) -> Option<Session> {
+        let nvs = self.nvs.as_ref()?;
+        let len = nvs.blob_len(NVS_KEY).ok()??;
+        let mut buf = vec![0; len];
+        let data = nvs.get_raw(NVS_KEY, &mut buf).ok()??;
+        postcard::from_bytes(data).ok()
+    }
+
+    fn save(&mut self, session: &Session) {
+        let Some(nvs) = self.nvs.as_mut() else {
+            return;
+        };
+        let result = postcard::to_allocvec(session)
+            .map_err(anyhow::Error::from)
+            .and_then(|data| Ok(nvs.set_raw(NVS_KEY, &data)?));
+        if let Err(e) = result {
+            log::error!("Failed to save the OTA update: {:?}", e);
+        }
+    }
+
+    fn clear(&mut self) {
+        if let Some(nvs) = self.nvs.as_mut() {
+            if let Err(e) = nvs.remove(NVS_KEY) {
+                log::error!("Failed to remove the saved OTA update: {:?}", e);
+            }
+        }
+    }
+}
+
+// An update announced by a manifest. The image is decoded, written to the inactive OTA
+// partition and hashed meanwhile, and only booted if the digest matches.
+pub struct Update {
+    manifest: Manifest,
+    digest: [u8; 32],
+    decoder: Decoder<RunningImage>,
+    writer: Writer,
+    // Of the data sent, which is the image itself unless it is encoded
+    received: usize,
+    // Saved to the session store
+    saved: usize,
+    // Of the last chunk received
+    seq: u32,
+}
+
+// Partitions are static and only written by the owner of the update
+unsafe impl Send for Update {}
+
+impl Update {
+    pub fn begin(manifest: Manifest, sessions: &mut SessionStore) -> Result<Self, String> {
+        check_running_confirmed()?;
+        let partition = next_partition().ok_or("no OTA partition")?;
+        let digest = manifest.verify(partition.size as usize)?;
+        let base = running_image(&manifest)?;
+        log::info!(
+            "Starting OTA update to {}, {} bytes sent {:?}",
+            manifest.version,
+            manifest.transfer_size(),
+            manifest.encoding
+        );
+        let update = Self::new(manifest, digest, partition, base);
+        if update.manifest.encoding == Encoding::Raw {
+            sessions.save(&update.session());
+        }
+        Ok(update)
+    }
+
+    // The update saved before a reboot, if there is one that can be continued
+    pub fn resume(sessions: &mut SessionStore) -> Option<Self> {
+        let session = sessions.load()?;
+        match Self::restore(session) {
+            Ok(update) => {
+                log::info!(
+                    "Resuming OTA update to {} at {}/{}",
+                    update.manifest.version,
+                    update.received,
+                    update.manifest.size
+                );
+                Some(update)
+            }
+            Err(e) => {
+                log::warn!("Can't resume the OTA update: {}", e);
+                sessions.clear();
+                None
+            }
+        }
+    }
+
+    fn new(
+        manifest: Manifest,
+        digest: [u8; 32],
+        partition: &'static esp_partition_t,
+        base: RunningImage,
+    ) -> Self {
+        Self {
+            decoder: Decoder::new(manifest.encoding, base),
+            writer: Writer {
+                partition,
+                size: manifest.size,
+                hasher: Sha256::new(),
+                header: Vec::with_capacity(IMAGE_HEADER_LEN),
+                written: 0,
+                erased: 0,
+            },
+            manifest,
+            digest,
+            received: 0,
+            saved: 0,
+            seq: 0,
+        }
+    }
+
+    fn restore(session: Session) -> Result<Self, String> {
+        check_running_confirmed()?;
+        let partition = next_partition().ok_or("no OTA partition")?;
+        if partition.address != session.partition_address {
+            return Err("the OTA partition changed".to_string());
+        }
+        // The state of the decoder isn't saved
+        if session.manifest.encoding != Encoding::Raw {
+            return Err("encoded images can't be resumed".to_string());
+        }
+        // Verified again, the firmware may have changed meanwhile
+        let digest = session.manifest.verify(partition.size as usize)?;
+        if session.written > session.manifest.size || session.written % SECTOR_SIZE != 0 {
+            return Err(format!("invalid offset {}", session.written));
+        }
+
+        // The state of the hasher isn't saved either, so what was written is hashed again
+        let base = running_image(&session.manifest)?;
+        let mut update = Self::new(session.manifest, digest, partition, base);
+        update.writer.rehash(session.written)?;
+        update.received = session.written;
+        update.saved = session.written;
+        Ok(update)
+    }
+
+    // Whether the manifest announces this update again, to resume it
+    pub fn is_for(&self, manifest: &Manifest) -> bool {
+        self.manifest.sha256 == manifest.sha256 && self.manifest.signature == manifest.signature
+    }
+
+    // Where the chunk ends, if it fits into the data announced. The offset comes from the
+    // header of the chunk, so it may be anything.
+    pub fn check(&self, chunk: &Chunk) -> Result<usize, String> {
+        chunk
+            .offset
+            .checked_add(chunk.data.len())
+            .filter(|end| *end <= self.manifest.transfer_size())
+            .ok_or_else(|| {
+                format!(
+                    "chunk {} at {} ends beyond the {} bytes announced",
+                    chunk.seq,
+                    chunk.offset,
+                    self.manifest.transfer_size()
+                )
+            })
+    }
+
+    // Decodes and writes the data of the chunk that continues what was sent. Chunks already
+    // received are skipped, ones ahead are dropped, either way the ack tells where to continue.
+    pub fn write(&mut self, chunk: Chunk, sessions: &mut SessionStore) -> Result<Ack, String> {
+        let end = self.check(&chunk)?;
+        self.seq = chunk.seq;
+        if chunk.offset > self.received {
+            log::warn!(
+                "OTA chunk {} at {} skipped ahead of {}",
+                chunk.seq,
+                chunk.offset,
+                self.received
+            );
+            return Ok(self.ack());
+        }
+        if end <= self.received {
+            return Ok(self.ack());
+        }
+
+        let data = &chunk.data[self.received - chunk.offset..];
+        self.decoder.write(data, &mut self.writer)?;
+        self.received = end;
+
+        if self.manifest.encoding == Encoding::Raw && self.received - self.saved >= RESUME_INTERVAL
+        {
+            self.saved = self.received - self.received % RESUME_INTERVAL;
+            sessions.save(&self.session());
+        }
+        Ok(self.ack())
+    }
+
+    fn session(&self) -> Session {
+        Session {
+            manifest: self.manifest.clone(),
+            partition_address: self.writer.partition.address,
+            written: self.saved,
+        }
+    }
+
+    pub fn ack(&self) -> Ack {
+        Ack {
+            seq: self.seq,
+            offset: self.received,
+            size: self.manifest.transfer_size(),
+        }
+    }
+
+    // Bytes received and the size of the data sent
+    pub fn progress(&self) -> (usize, usize) {
+        (self.received, self.manifest.transfer_size())
+    }
+
+    // Shown on the display while the image is received
+    pub fn progress_event(&self) -> StateEvent {
+        let (written, size) = self.progress();
+        StateEvent::OtaProgress {
+            version: self.manifest.version.clone(),
+            written,
+            size,
+        }
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.received == self.manifest.transfer_size()
+    }
+
+    // Checks the digest, then sets the image to boot next, which ESP-IDF verifies again
+    pub fn finish(self, sessions: &mut SessionStore) -> Result<(), String> {
+        sessions.clear();
+        if !self.decoder.is_done() || self.writer.written != self.manifest.size {
+            return Err(format!(
+                "decoded {} of the {} bytes announced",
+                self.writer.written, self.manifest.size
+            ));
+        }
+        if self.writer.hasher.finalize()[..] != self.digest {
+            return Err("SHA-256 mismatch".to_string());
+        }
+        esp!(unsafe { esp_ota_set_boot_partition(self.writer.partition) })
+            .map_err(|e| format!("failed to set boot partition: {:?}", e))
+    }
+
+    // The partition is left as is, it is only booted once set as the boot partition
+    pub fn abort(self, sessions: &mut SessionStore) {
+        log::info!("OTA update to {} aborted", self.manifest.version);
+        sessions.clear();
+    }
+}
+
+// Writes the decoded image to the partition
+struct Writer {
+    partition: &'static esp_partition_t,
+    // Of the image
+    size: usize,
+    hasher: Sha256,
+    // Collected until the image header can be checked
+    header: Vec<u8>,
+    written: usize,
+    // Sectors are erased right before they are written
+    erased: usize,
+}
+
+impl Sink for Writer {
+    fn write(&mut self, data: &[u8]) -> Result<(), String> {
+        if self.written + data.len() > self.size {
+            return Err(format!(
+                "image larger than the {} bytes announced",
+                self.size
+            ));
+        }
+        self.collect_header(data)?;
+        self.erase_to(self.written + data.len())?;
+        esp!(unsafe {
+            esp_partition_write(
+                self.partition,
+                self.written,
+                data.as_ptr() as *const _,
+                data.len(),
+            )
+        })
+        .map_err(|e| format!("failed to write: {:?}", e))?;
+        self.hasher.update(data);
+        self.written += data.len();
+        Ok(())
+    }
+}
+
+impl Writer {
+    // Hashes what was written before a reboot, up to the sector aligned `to`
+    fn rehash(&mut self, to: usize) -> Result<(), String> {
+        let mut buf = vec![0; SECTOR_SIZE];
+        while self.written < to {
+            read_partition(self.partition, self.written, &mut buf)?;
+            self.collect_header(&buf)?;
+            self.hasher.update(&buf);
+            self.written += buf.len();
+        }
+        self.erased = self.written;
+        Ok(())
+    }
+
+    fn collect_header(&mut self, data: &[u8]) -> Result<(), String> {
+        if self.header.len() < IMAGE_HEADER_LEN {
+            let len = data.len().min(IMAGE_HEADER_LEN - self.header.len());
+            self.header.extend_from_slice(&data[..len]);
+            if self.header.len() == IMAGE_HEADER_LEN {
+                check_header(&self.header)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn erase_to(&mut self, end: usize) -> Result<(), String> {
+        if end <= self.erased {
+            return Ok(());
+        }
+        // Partitions are sector aligned, so this doesn't go beyond the end
+        let to = (end + SECTOR_SIZE - 1) / SECTOR_SIZE * SECTOR_SIZE;
+        esp!(unsafe { esp_partition_erase_range(self.partition, self.erased, to - self.erased) })
+            .map_err(|e| format!("failed to erase: {:?}", e))?;
+        self.erased = to;
+        Ok(())
+    }
+}
+
+// The image in the running partition, which deltas are applied to
+struct RunningImage {
+    partition: &'static esp_partition_t,
+    // Of the delta base, zero for other encodings
+    size: usize,
+}
+
+impl Base for RunningImage {
+    fn size(&self) -> usize {
+        self.size
+    }
+
+    fn read(&self, offset: usize, buf: &mut [u8]) -> Result<(), String> {
+        read_partition(self.partition, offset, buf)
+    }
+}
+
+// The running image, checked against the base of a delta
+fn running_image(manifest: &Manifest) -> Result<RunningImage, String> {
+    let partition =
+        unsafe { esp_ota_get_running_partition().as_ref() }.ok_or("no running partition")?;
+    let mut image = RunningImage { partition, size: 0 };
+    if manifest.encoding != Encoding::Delta {
+        return Ok(image);
+    }
+
+    let (Some(size), Some(digest)) = (
+        manifest.base_size,
+        manifest.base_sha256.as_deref().and_then(parse_digest),
+    ) else {
+        return Err("delta without base_size and base_sha256".to_string());
+    };
+    if size > partition.size as usize {
+        return Err(format!("invalid base size {}", size));
+    }
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; SECTOR_SIZE];
+    let mut offset = 0;
+    while offset < size {
+        let len = (size - offset).min(buf.len());
+        read_partition(partition, offset, &mut buf[..len])?;
+        hasher.update(&buf[..len]);
+        offset += len;
+    }
+    if hasher.finalize()[..] != digest {
+        return Err("the delta is for another image than the running one".to_string());
+    }
+    image.size = size;
+    Ok(image)
+}
+
+fn read_partition(
+    partition: &esp_partition_t,
+    offset: usize,
+    buf: &mut [u8],
+) -> Result<(), String> {
+    esp!(unsafe { esp_partition_read(partition, offset, buf.as_mut_ptr() as *mut _, buf.len()) })
+        .map_err(|e| format!("failed to read: {:?}", e))
+}
+
+// Whether `signature`, base64 encoded, is the signature of `message` by the OTA key. Also
+// used for signed commands.
+pub fn verify_signature(message: &str, signature: &str) -> Result<(), String> {
+    if PUBLIC_KEY.is_empty() {
+        return Err("no public key configured".to_string());
+    }
+    let key = VerifyingKey::try_from(PUBLIC_KEY).map_err(|_| "invalid public key")?;
+    let signature = base64::engine::general_purpose::STANDARD
+        .decode(signature)
+        .ok()
+        .and_then(|signature| Signature::from_slice(&signature).ok())
+        .ok_or("invalid signature")?;
+    key.verify(message.as_bytes(), &signature)
+        .map_err(|_| "signature verification failed".to_string())
+}
+
+// The previous image is the one to roll back to, until the running one is confirmed
+fn check_running_confirmed() -> Result<(), String> {
+    if health::is_pending_verify() {
+        return Err("the running image is not confirmed yet".to_string());
+    }
+    Ok(())
+}
+
+fn check_header(header: &[u8]) -> Result<(), String> {
+    if header[0] != IMAGE_MAGIC {
+        return Err("not an ESP-IDF app image".to_string());
+    }
+    let chip_id = u16::from_le_bytes([header[12], header[13]]);
+    if u32::from(chip_id) != esp_idf_svc::sys::CONFIG_IDF_FIRMWARE_CHIP_ID {
+        return Err(format!("image is for chip ID {}", chip_id));
+    }
+    Ok(())
+}
+
+fn target() -> &'static str {
+    std::ffi::CStr::from_bytes_with_nul(esp_idf_svc::sys::CONFIG_IDF_TARGET)
+        .ok()
+        .and_then(|target| target.to_str().ok())
+        .unwrap_or_default()
+}
+
+fn next_partition() -> Option<&'static esp_partition_t> {
+    // Partitions are loaded from the partition table once, and never freed
+    unsafe { esp_ota_get_next_update_partition(std::ptr::null()).as_ref() }
+}
+
+fn parse_digest(hex: &str) -> Option<[u8; 32]> {
+    let mut digest = [0; 32];
+    if hex.len() != digest.len() * 2 {
+        return None;
+    }
+    for (i, byte) in digest.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(digest)
+}
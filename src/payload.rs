@@ -0,0 +1,20 @@
+use serde::de::DeserializeOwned;
+
+// Feed payloads are JSON by default. Payloads starting with this byte, which can't start a JSON
+// document, are postcard encoded instead, with the same fields in the same order. Bumped
+// whenever the fields change, since postcard can't tell a different layout apart.
+pub const POSTCARD_FORMAT: u8 = 0x02;
+// Before the generation fields were added, see docs/mqtt.md
+const OLD_POSTCARD_FORMAT: u8 = 0x01;
+
+pub fn decode<T: DeserializeOwned>(data: &[u8]) -> anyhow::Result<T> {
+    match data.split_first() {
+        Some((&POSTCARD_FORMAT, rest)) => Ok(postcard::from_bytes(rest)?),
+        Some((&OLD_POSTCARD_FORMAT, _)) => anyhow::bail!(
+            "postcard format {:#04x} is no longer supported, use {:#04x}",
+            OLD_POSTCARD_FORMAT,
+            POSTCARD_FORMAT
+        ),
+        _ => Ok(serde_json::from_slice(data)?),
+    }
+}
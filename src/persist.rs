@@ -0,0 +1,72 @@
+use chrono::{DateTime, Utc};
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use serde::{Deserialize, Serialize};
+
+use crate::feed::{Metro, Notification, Tram};
+
+const NAMESPACE: &str = "tramcast";
+const KEY: &str = "state";
+// Stored before the snapshot, bumped whenever its layout or the one of the feed payloads
+// changes. Snapshots from before it was introduced start with the length of `saved_at`,
+// never 2 or less.
+const VERSION: u8 = 2;
+
+// The last received state, restored after a reboot. Stored postcard encoded, after `VERSION`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Snapshot {
+    pub saved_at: DateTime<Utc>,
+    pub tram: Option<Tram>,
+    pub tram_received_at: Option<DateTime<Utc>>,
+    pub metro: Option<Metro>,
+    pub metro_received_at: Option<DateTime<Utc>>,
+    pub notifications: Vec<Notification>,
+}
+
+pub struct Store {
+    // `None` if the NVS namespace couldn't be opened, then nothing is persisted
+    nvs: Option<EspNvs<NvsDefault>>,
+}
+
+impl Store {
+    pub fn new(partition: EspDefaultNvsPartition) -> Self {
+        let nvs = EspNvs::new(partition, NAMESPACE, true)
+            .map_err(|e| log::error!("Failed to open NVS namespace {}: {:?}", NAMESPACE, e))
+            .ok();
+        Self { nvs }
+    }
+
+    pub fn load(&mut self) -> Option<Snapshot> {
+        let nvs = self.nvs.as_mut()?;
+        let len = nvs.blob_len(KEY).ok()??;
+        let mut buf = vec![0; len];
+        let data = nvs.get_raw(KEY, &mut buf).ok()??;
+        let snapshot = match data.split_first() {
+            Some((&VERSION, rest)) => postcard::from_bytes(rest)
+                .map_err(|e| log::warn!("Failed to decode the saved state: {:?}", e))
+                .ok(),
+            _ => {
+                log::warn!("Dropping the state saved by a firmware with a different format");
+                None
+            }
+        };
+        // It would be misread again after the next reboot, if nothing is saved until then
+        if snapshot.is_none() {
+            if let Err(e) = nvs.remove(KEY) {
+                log::error!("Failed to remove the saved state: {:?}", e);
+            }
+        }
+        snapshot
+    }
+
+    pub fn save(&mut self, snapshot: &Snapshot) {
+        let Some(nvs) = self.nvs.as_mut() else {
+            return;
+        };
+        let result = postcard::to_extend(snapshot, vec![VERSION])
+            .map_err(anyhow::Error::from)
+            .and_then(|data| Ok(nvs.set_raw(KEY, &data)?));
+        if let Err(e) = result {
+            log::error!("Failed to save the state: {:?}", e);
+        }
+    }
+}
@@ -0,0 +1,141 @@
+use std::{
+    sync::{mpsc::Sender, Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::bail;
+use chrono::{DateTime, Utc};
+use embedded_svc::{http::client::Client, io::Read};
+use esp_idf_svc::http::client::{Configuration, EspHttpConnection};
+
+use crate::{
+    clock::Clock,
+    feed::{Metro, Tram},
+    json_feed, payload,
+    state::StateEvent,
+};
+
+// Feeds polled directly over HTTP, instead of being published to the broker
+struct HttpFeed {
+    name: &'static str,
+    url: &'static str,
+    interval_secs: &'static str,
+    // JSON pointer to the list of departures, the response is a feed payload if empty
+    departures: &'static str,
+    // Comma separated fields of a departure holding its UNIX time
+    time_fields: &'static str,
+    event: fn(Option<DateTime<Utc>>, Option<i64>) -> StateEvent,
+}
+
+const HTTP_FEEDS: &[HttpFeed] = &[
+    HttpFeed {
+        name: "tram",
+        url: env!("ESP_HTTP_TRAM_URL"),
+        interval_secs: env!("ESP_HTTP_TRAM_INTERVAL_SECS"),
+        departures: env!("ESP_HTTP_TRAM_DEPARTURES"),
+        time_fields: env!("ESP_HTTP_TRAM_TIME_FIELDS"),
+        event: |depart_at, time_left_ms| {
+            // Polled by a single thread, the updates can't arrive out of order
+            StateEvent::TramStateChanged(Tram {
+                depart_at,
+                time_left_ms,
+                generated_at: None,
+                seq: None,
+            })
+        },
+    },
+    HttpFeed {
+        name: "metro",
+        url: env!("ESP_HTTP_METRO_URL"),
+        interval_secs: env!("ESP_HTTP_METRO_INTERVAL_SECS"),
+        departures: env!("ESP_HTTP_METRO_DEPARTURES"),
+        time_fields: env!("ESP_HTTP_METRO_TIME_FIELDS"),
+        event: |depart_at, time_left_ms| {
+            StateEvent::MetroStateChanged(Metro {
+                depart_at,
+                time_left_ms,
+                generated_at: None,
+                seq: None,
+            })
+        },
+    },
+];
+
+pub fn is_enabled() -> bool {
+    HTTP_FEEDS.iter().any(|feed| !feed.url.is_empty())
+}
+
+// Started by the MQTT thread, which brings up WiFi and the clock
+pub fn poll_thread(tx: Sender<StateEvent>, clock: Arc<Mutex<Clock>>) -> ! {
+    let feeds: Vec<&HttpFeed> = HTTP_FEEDS
+        .iter()
+        .filter(|feed| !feed.url.is_empty())
+        .collect();
+    let mut next_poll = vec![Instant::now(); feeds.len()];
+
+    loop {
+        // Departures can't be told from past ones before the clock is set, by SNTP or the
+        // broker. This also waits for WiFi, which the clock needs first.
+        if clock.lock().unwrap().source().is_none() {
+            log::info!("Waiting for the clock to be set before polling");
+            while clock.lock().unwrap().source().is_none() {
+                std::thread::sleep(Duration::from_secs(1));
+            }
+        }
+
+        for (feed, next_poll) in feeds.iter().zip(next_poll.iter_mut()) {
+            if Instant::now() < *next_poll {
+                continue;
+            }
+            *next_poll = Instant::now() + Duration::from_secs(feed.interval_secs.parse().unwrap());
+
+            // Failures are only logged, the last known departure stays on the screen
+            match poll(feed) {
+                Ok(depart_at) => {
+                    log::info!("Polled {} departure: {:?}", feed.name, depart_at);
+                    let time_left_ms = depart_at.map(|at| (at - Utc::now()).num_milliseconds());
+                    tx.send((feed.event)(depart_at, time_left_ms)).unwrap();
+                }
+                Err(e) => log::error!("Failed to poll {} feed: {:?}", feed.name, e),
+            }
+        }
+
+        let until_next = next_poll
+            .iter()
+            .min()
+            .map_or(Duration::from_secs(1), |next| {
+                next.saturating_duration_since(Instant::now())
+            });
+        std::thread::sleep(until_next);
+    }
+}
+
+fn poll(feed: &HttpFeed) -> anyhow::Result<Option<DateTime<Utc>>> {
+    let connection = EspHttpConnection::new(&Configuration {
+        crt_bundle_attach: Some(esp_idf_svc::sys::esp_crt_bundle_attach),
+        ..Default::default()
+    })?;
+    let mut client = Client::wrap(connection);
+    let mut response = client.get(feed.url)?.submit()?;
+    if response.status() != 200 {
+        bail!("unexpected HTTP status {}", response.status());
+    }
+
+    let mut body = Vec::new();
+    let mut buf = [0; 512];
+    loop {
+        let len = response.read(&mut buf)?;
+        if len == 0 {
+            break;
+        }
+        body.extend_from_slice(&buf[..len]);
+    }
+
+    if feed.departures.is_empty() {
+        // Tram and metro payloads have the same fields
+        return Ok(payload::decode::<Tram>(&body)?.depart_at);
+    }
+    let document: serde_json::Value = serde_json::from_slice(&body)?;
+    let time_fields: Vec<&str> = feed.time_fields.split(',').collect();
+    json_feed::next_departure(&document, feed.departures, &time_fields, Utc::now())
+}
@@ -0,0 +1,57 @@
+use std::time::Duration;
+
+// Resuming OTA downloads, shared with the bridge tests, see docs/ota.md
+
+// Failed downloads are resumed this many times, waiting longer after each failure. The count
+// starts over once some data was received.
+pub const RETRIES: u32 = 5;
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+pub enum Failure {
+    // Retried, resuming where it stopped
+    Network(String),
+    // The image is not the announced one
+    Rejected(String),
+    Aborted,
+}
+
+// The `Range` header requesting the image from the `written` bytes on
+pub fn range(written: usize) -> String {
+    format!("bytes={}-", written)
+}
+
+// Where in the image the body of a response to `range(written)` starts. Servers without
+// range support send the whole image, what was written is skipped then.
+pub fn body_offset(status: u16, written: usize) -> Result<usize, Failure> {
+    match status {
+        206 => Ok(written),
+        200 => Ok(0),
+        400..=499 => Err(Failure::Rejected(format!("HTTP status {}", status))),
+        _ => Err(Failure::Network(format!("HTTP status {}", status))),
+    }
+}
+
+// Failed attempts in a row
+#[derive(Default)]
+pub struct Retries {
+    count: u32,
+}
+
+impl Retries {
+    // How long to wait before resuming after a network failure, `None` once given up.
+    // `progressed` is whether the failed attempt received any data.
+    pub fn next_delay(&mut self, progressed: bool) -> Option<Duration> {
+        if progressed {
+            self.count = 0;
+        }
+        if self.count >= RETRIES {
+            return None;
+        }
+        self.count += 1;
+        Some(RETRY_DELAY * self.count)
+    }
+
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+}
@@ -0,0 +1,98 @@
+use std::{
+    ops::{Deref, DerefMut},
+    sync::Mutex,
+};
+
+use embedded_graphics::{pixelcolor::BinaryColor, prelude::*, primitives::Rectangle};
+
+pub const WIDTH: usize = 128;
+pub const HEIGHT: usize = 64;
+const FRAME_LEN: usize = WIDTH * HEIGHT / 8;
+
+// The last frame flushed to the display, returned by the screenshot command
+static FRAME: Mutex<[u8; FRAME_LEN]> = Mutex::new([0; FRAME_LEN]);
+
+// Keeps a copy of the pixels drawn on the display, since the driver doesn't expose its
+// buffer. Rows of 16 bytes, the leftmost pixel in the most significant bit, 1 is lit.
+pub struct Recorder<D> {
+    dev: D,
+    frame: [u8; FRAME_LEN],
+}
+
+impl<D> Recorder<D> {
+    pub fn new(dev: D) -> Self {
+        Self {
+            dev,
+            frame: [0; FRAME_LEN],
+        }
+    }
+
+    // Called once the frame is flushed, so a screenshot never shows half of a redraw
+    pub fn save(&self) {
+        *FRAME.lock().unwrap() = self.frame;
+    }
+}
+
+#[cfg(not(feature = "simulated"))]
+pub fn frame() -> Vec<u8> {
+    FRAME.lock().unwrap().to_vec()
+}
+
+fn set_pixel(frame: &mut [u8; FRAME_LEN], Pixel(point, color): Pixel<BinaryColor>) {
+    let (Ok(x), Ok(y)) = (usize::try_from(point.x), usize::try_from(point.y)) else {
+        return;
+    };
+    if x >= WIDTH || y >= HEIGHT {
+        return;
+    }
+    let mask = 0x80 >> (x % 8);
+    let byte = &mut frame[(y * WIDTH + x) / 8];
+    if color.is_on() {
+        *byte |= mask;
+    } else {
+        *byte &= !mask;
+    }
+}
+
+impl<D: Dimensions> Dimensions for Recorder<D> {
+    fn bounding_box(&self) -> Rectangle {
+        self.dev.bounding_box()
+    }
+}
+
+impl<D> DrawTarget for Recorder<D>
+where
+    D: DrawTarget<Color = BinaryColor>,
+{
+    type Color = BinaryColor;
+    type Error = D::Error;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        let frame = &mut self.frame;
+        self.dev
+            .draw_iter(pixels.into_iter().inspect(|pixel| set_pixel(frame, *pixel)))
+    }
+
+    fn clear(&mut self, color: Self::Color) -> Result<(), Self::Error> {
+        self.frame.fill(if color.is_on() { 0xff } else { 0 });
+        self.dev.clear(color)
+    }
+}
+
+// The driver methods, like `flush` and `set_brightness`
+impl<D> Deref for Recorder<D> {
+    type Target = D;
+
+    fn deref(&self) -> &D {
+        &self.dev
+    }
+}
+
+impl<D> DerefMut for Recorder<D> {
+    fn deref_mut(&mut self) -> &mut D {
+        &mut self.dev
+    }
+}
@@ -5,7 +5,10 @@ use esp_idf_svc::{
     timer::EspTaskTimerService,
 };
 
-use crate::state::{StateEvent, Tram};
+use crate::{
+    feed::Tram,
+    state::{StateEvent, TimeSource},
+};
 
 pub async fn mqtt_thread(
     tx: Sender<StateEvent>,
@@ -17,7 +20,8 @@ pub async fn mqtt_thread(
     std::thread::sleep(std::time::Duration::from_secs(2));
     tx.send(StateEvent::WifiConnected(true)).unwrap();
     std::thread::sleep(std::time::Duration::from_secs(2));
-    tx.send(StateEvent::TimeSynced(true)).unwrap();
+    tx.send(StateEvent::TimeSynced(Some(TimeSource::Sntp)))
+        .unwrap();
     std::thread::sleep(std::time::Duration::from_secs(2));
     tx.send(StateEvent::MqttConnected(true)).unwrap();
     std::thread::sleep(std::time::Duration::from_secs(2));
@@ -26,6 +30,8 @@ pub async fn mqtt_thread(
         tx.send(StateEvent::TramStateChanged(Tram {
             depart_at: Some(chrono::Utc::now() + chrono::TimeDelta::try_minutes(5).unwrap()),
             time_left_ms: Some(5 * 60 * 1000),
+            generated_at: None,
+            seq: None,
         }))
         .unwrap();
         std::thread::sleep(std::time::Duration::from_secs(15));
@@ -1,25 +1,95 @@
-use serde::Deserialize;
-
-#[derive(Deserialize, Debug, Clone)]
-pub struct Tram {
-    #[serde(rename = "departAt")]
-    pub depart_at: Option<chrono::DateTime<chrono::Utc>>,
-    #[serde(rename = "timeLeftMs")]
-    pub time_left_ms: Option<i64>,
-}
+use std::sync::atomic::{AtomicU32, Ordering};
 
-#[derive(Deserialize, Debug, Clone)]
-pub struct Metro {
-    #[serde(rename = "departAt")]
-    pub depart_at: Option<chrono::DateTime<chrono::Utc>>,
-    #[serde(rename = "timeLeftMs")]
-    pub time_left_ms: Option<i64>,
-}
+use serde::Serialize;
+
+use crate::{
+    draw::Screen,
+    feed::{Alerts, Generation, Metro, Notification, Tram},
+};
 
 pub enum StateEvent {
     WifiConnected(bool),
     MqttConnected(bool),
-    TimeSynced(bool),
+    // `None` while the clock isn't synced, or the last sync is too old to be trusted
+    TimeSynced(Option<TimeSource>),
     TramStateChanged(Tram),
     MetroStateChanged(Metro),
+    ShowScreen(Screen),
+    RotationPaused(bool),
+    BrightnessChanged(u8),
+    NotificationReceived(Notification),
+    // The full list of alerts, replacing the previous one
+    AlertsChanged(Alerts),
+    // An OTA update started or progressed, `written` out of `size` bytes
+    OtaProgress {
+        version: String,
+        written: usize,
+        size: usize,
+    },
+    // The OTA update was verified, the device is about to restart
+    OtaRebooting,
+    // The OTA update was rejected or aborted
+    OtaFailed(String),
+}
+
+// Where the time of the clock came from
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum TimeSource {
+    Sntp,
+    // A timestamp received from the broker, see docs/time.md
+    Broker,
+}
+
+// Updates of a feed accepted and rejected by the display, reported by the status command
+pub struct UpdateCounters {
+    accepted: AtomicU32,
+    duplicates: AtomicU32,
+    out_of_order: AtomicU32,
+}
+
+pub static TRAM_UPDATES: UpdateCounters = UpdateCounters::new();
+pub static METRO_UPDATES: UpdateCounters = UpdateCounters::new();
+pub static ALERT_UPDATES: UpdateCounters = UpdateCounters::new();
+pub static NOTIFICATION_UPDATES: UpdateCounters = UpdateCounters::new();
+
+#[derive(Serialize, Debug)]
+pub struct UpdateStats {
+    pub accepted: u32,
+    pub duplicates: u32,
+    pub out_of_order: u32,
+}
+
+impl UpdateCounters {
+    const fn new() -> Self {
+        Self {
+            accepted: AtomicU32::new(0),
+            duplicates: AtomicU32::new(0),
+            out_of_order: AtomicU32::new(0),
+        }
+    }
+
+    // Whether an update is newer than the current data. Updates that can't be ordered against
+    // it, e.g. without a generation, are always accepted, and so is anything when there is no
+    // current data to compare with.
+    pub fn accept(&self, current: Option<Generation>, update: Generation) -> bool {
+        let order = current
+            .and_then(|current| update.compare(&current))
+            .unwrap_or(std::cmp::Ordering::Greater);
+        let counter = match order {
+            std::cmp::Ordering::Greater => &self.accepted,
+            std::cmp::Ordering::Equal => &self.duplicates,
+            std::cmp::Ordering::Less => &self.out_of_order,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        order == std::cmp::Ordering::Greater
+    }
+
+    pub fn stats(&self) -> UpdateStats {
+        UpdateStats {
+            accepted: self.accepted.load(Ordering::Relaxed),
+            duplicates: self.duplicates.load(Ordering::Relaxed),
+            out_of_order: self.out_of_order.load(Ordering::Relaxed),
+        }
+    }
 }
@@ -0,0 +1,164 @@
+use chrono::{DateTime, Datelike, Duration, NaiveDate, NaiveTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+
+// Scheduled departures of the configured stops, shown while realtime data is stale.
+// Compiled from a GTFS static feed by the bridge, see docs/timetable.md. Shared with
+// the bridge, and stored postcard encoded. Only the bridge builds it, the firmware reads it
+// through `EncodedTimetable`.
+#[cfg(not(target_os = "espidf"))]
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct Timetable {
+    // IANA name of the timezone of the stop times
+    pub timezone: String,
+    pub services: Vec<Service>,
+    pub tram: Vec<Departure>,
+    pub metro: Vec<Departure>,
+}
+
+// The dates a service runs on
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
+pub struct Service {
+    // Bit 0 is Monday
+    pub weekdays: u8,
+    // Days since 0001-01-01, as in `NaiveDate::num_days_from_ce`
+    pub start_date: Option<i32>,
+    pub end_date: Option<i32>,
+    pub added: Vec<i32>,
+    pub removed: Vec<i32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct Departure {
+    // Index into `services`
+    pub service: u16,
+    // Seconds since noon minus 12 hours of the service day, may be over 24 hours
+    pub time: u32,
+}
+
+impl Service {
+    pub fn runs_on(&self, date: NaiveDate) -> bool {
+        let day = date.num_days_from_ce();
+        if self.added.contains(&day) {
+            return true;
+        }
+        if self.removed.contains(&day) {
+            return false;
+        }
+        self.start_date.is_some_and(|start| start <= day)
+            && self.end_date.is_some_and(|end| day <= end)
+            && self.weekdays & (1 << date.weekday().num_days_from_monday()) != 0
+    }
+}
+
+#[cfg(not(target_os = "espidf"))]
+impl Timetable {
+    pub fn is_empty(&self) -> bool {
+        self.tram.is_empty() && self.metro.is_empty()
+    }
+}
+
+// A `Timetable` read from its encoding, as embedded into the firmware. The departures are
+// decoded while iterating them, only the few services are kept in memory.
+pub struct EncodedTimetable<'a> {
+    timezone: Tz,
+    services: Vec<Service>,
+    pub tram: Departures<'a>,
+    pub metro: Departures<'a>,
+}
+
+// The encoded departures of a screen
+#[derive(Default, Clone, Copy)]
+pub struct Departures<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> EncodedTimetable<'a> {
+    // The whole encoding is checked once, so decoding the departures later can't fail
+    pub fn decode(data: &'a [u8]) -> anyhow::Result<Self> {
+        let (timezone, rest) = postcard::take_from_bytes::<&str>(data)?;
+        let timezone = timezone
+            .parse()
+            .map_err(|_| anyhow::anyhow!("unknown timezone {}", timezone))?;
+        let (services, rest) = postcard::take_from_bytes(rest)?;
+        let (tram, rest) = Departures::take(rest)?;
+        let (metro, _) = Departures::take(rest)?;
+        Ok(Self {
+            timezone,
+            services,
+            tram,
+            metro,
+        })
+    }
+
+    // The earliest of `departures` that is not in the past
+    pub fn next_departure(
+        &self,
+        departures: &Departures,
+        now: DateTime<Utc>,
+    ) -> Option<DateTime<Utc>> {
+        let today = now.with_timezone(&self.timezone).date_naive();
+
+        // Trips of the previous service day may run past midnight
+        [today.pred_opt()?, today, today.succ_opt()?]
+            .into_iter()
+            .filter_map(|day| Some((day, service_day_start(self.timezone, day)?)))
+            .flat_map(|(day, start)| {
+                departures
+                    .iter()
+                    .filter(move |departure| {
+                        self.services
+                            .get(departure.service as usize)
+                            .is_some_and(|service| service.runs_on(day))
+                    })
+                    .map(move |departure| start + Duration::seconds(departure.time.into()))
+            })
+            .filter(|time| *time >= now)
+            .min()
+    }
+}
+
+impl Default for EncodedTimetable<'_> {
+    fn default() -> Self {
+        Self {
+            timezone: Tz::UTC,
+            services: Vec::new(),
+            tram: Departures::default(),
+            metro: Departures::default(),
+        }
+    }
+}
+
+impl<'a> Departures<'a> {
+    // Splits the encoded list of departures off the start of `data`
+    fn take(data: &'a [u8]) -> postcard::Result<(Self, &'a [u8])> {
+        let (len, mut rest) = postcard::take_from_bytes::<usize>(data)?;
+        for _ in 0..len {
+            rest = postcard::take_from_bytes::<Departure>(rest)?.1;
+        }
+        let departures = Self {
+            data: &data[..data.len() - rest.len()],
+        };
+        Ok((departures, rest))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Departure> + 'a {
+        // Skips the length, the departures were checked by `take`
+        let mut data =
+            postcard::take_from_bytes::<usize>(self.data).map_or(&[][..], |(_, rest)| rest);
+        std::iter::from_fn(move || {
+            let (departure, rest) = postcard::take_from_bytes(data).ok()?;
+            data = rest;
+            Some(departure)
+        })
+    }
+}
+
+// Stop times are relative to noon minus 12 hours, which is midnight except on the
+// days of daylight saving time changes
+pub fn service_day_start(timezone: Tz, day: NaiveDate) -> Option<DateTime<Utc>> {
+    let noon = timezone
+        .from_local_datetime(&day.and_time(NaiveTime::from_hms_opt(12, 0, 0)?))
+        .single()?;
+    Some(noon.with_timezone(&Utc) - Duration::hours(12))
+}
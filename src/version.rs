@@ -0,0 +1,79 @@
+use std::cmp::Ordering;
+
+use serde::Serialize;
+
+// The version of Cargo.toml, with the output of `git describe` as build metadata, e.g.
+// `0.2.0+v0.1.0-5-g1a2b3c4`
+pub const VERSION: &str = env!("ESP_VERSION");
+
+// Found in the image by `tramcast-sign`, which writes it into the manifest
+#[used]
+static VERSION_MARKER: &str = concat!("tramcast-version:", env!("ESP_VERSION"), "\0");
+
+#[derive(Serialize, Debug)]
+pub struct Build {
+    pub version: &'static str,
+    // Empty if built outside a git checkout
+    pub git_describe: &'static str,
+    // UNIX time
+    pub built_at: u64,
+    pub profile: &'static str,
+    pub idf_version: &'static str,
+}
+
+pub fn build() -> Build {
+    Build {
+        version: VERSION,
+        git_describe: env!("ESP_GIT_DESCRIBE"),
+        built_at: env!("ESP_BUILT_AT").parse().unwrap(),
+        profile: env!("ESP_BUILD_PROFILE"),
+        idf_version: unsafe { std::ffi::CStr::from_ptr(esp_idf_svc::sys::esp_get_idf_version()) }
+            .to_str()
+            .unwrap_or_default(),
+    }
+}
+
+// Orders semantic versions, ignoring build metadata. `None` if either is invalid.
+pub fn compare(a: &str, b: &str) -> Option<Ordering> {
+    let (a_core, a_pre) = parse(a)?;
+    let (b_core, b_pre) = parse(b)?;
+    // A pre-release is older than the release
+    let pre = match (a_pre.is_empty(), b_pre.is_empty()) {
+        (true, true) => Ordering::Equal,
+        (true, false) => Ordering::Greater,
+        (false, true) => Ordering::Less,
+        (false, false) => compare_pre_release(&a_pre, &b_pre),
+    };
+    Some(a_core.cmp(&b_core).then(pre))
+}
+
+// The major, minor and patch numbers, and the pre-release identifiers
+fn parse(version: &str) -> Option<([u64; 3], Vec<&str>)> {
+    let version = version.split('+').next()?;
+    let (core, pre) = match version.split_once('-') {
+        Some((core, pre)) => (core, pre.split('.').collect()),
+        None => (version, Vec::new()),
+    };
+    let mut numbers = core.split('.').map(|number| number.parse().ok());
+    let core = [numbers.next()??, numbers.next()??, numbers.next()??];
+    if numbers.next().is_some() {
+        return None;
+    }
+    Some((core, pre))
+}
+
+fn compare_pre_release(a: &[&str], b: &[&str]) -> Ordering {
+    for (a, b) in a.iter().zip(b) {
+        // Numeric identifiers are older than alphanumeric ones
+        let ordering = match (a.parse::<u64>(), b.parse::<u64>()) {
+            (Ok(a), Ok(b)) => a.cmp(&b),
+            (Ok(_), Err(_)) => Ordering::Less,
+            (Err(_), Ok(_)) => Ordering::Greater,
+            (Err(_), Err(_)) => a.cmp(b),
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    a.len().cmp(&b.len())
+}